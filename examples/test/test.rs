@@ -14,6 +14,7 @@ async fn main() -> io::Result<()> {
         post_handler: Some(|path, query, body| result_handler(path, query, Some(body))),
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await
 }
\ No newline at end of file
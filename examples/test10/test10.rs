@@ -0,0 +1,42 @@
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+extern crate parfait;
+use parfait::*;
+
+get!("/", home_handler => ContentType::String("Hello, world"), "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n");
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let handler = Handler {
+        get_handler: Some(|path, query| home_handler(path, query, None)),
+        post_handler: None,
+        put_handler: None,
+        delete_handler: None,
+        patch_handler: None,
+    };
+
+    // /healthz and /readyz report these checks, refreshed every five seconds
+    let health = Arc::new(
+        HealthMiddleware::new()
+            .liveness_check("event_loop", Duration::from_secs(1), || async { Ok(()) })
+            .readiness_check("data_file", Duration::from_secs(2), || async {
+                tokio::fs::metadata("examples/test4/file.json")
+                    .await
+                    .map(|_| ())
+                    .map_err(|e| e.to_string())
+            }),
+    );
+    health.spawn_checks(Duration::from_secs(5));
+
+    // On Ctrl+C, report not ready and give the load balancer time to stop sending traffic
+    let shutdown = health.clone();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        shutdown.begin_shutdown();
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        std::process::exit(0);
+    });
+
+    run_with_middleware("127.0.0.1", 8080, handler, health).await
+}
@@ -0,0 +1,19 @@
+use std::io;
+extern crate parfait;
+use parfait::*;
+
+// GET/POST /items, GET/PUT/PATCH/DELETE /items/{id}
+json_resource!("/items", items => "examples/test11/items.json");
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let handler = Handler {
+        get_handler: Some(items::get),
+        post_handler: Some(items::post),
+        put_handler: Some(items::put),
+        delete_handler: Some(items::delete),
+        patch_handler: Some(items::patch),
+    };
+
+    run("127.0.0.1", 8080, handler).await
+}
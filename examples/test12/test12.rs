@@ -0,0 +1,34 @@
+use std::io;
+use std::time::Duration;
+extern crate parfait;
+use parfait::*;
+
+// Shared by every route below; /kv/{key} addresses one entry, /kv the whole store
+static STORE: JsonStore = JsonStore::new();
+
+get!("/kv", read_entry => ContentType::Store(&STORE), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n");
+post!("/kv", create_entry => ContentType::Store(&STORE), "application/json");
+put!("/kv", replace_entry => ContentType::Store(&STORE), "application/json");
+delete!("/kv", delete_entry => ContentType::Store(&STORE), "");
+patch!("/kv", patch_entry => ContentType::Store(&STORE));
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    // Start from the last snapshot and save every 30 seconds
+    STORE.load("examples/test12/store.json")?;
+    STORE.spawn_snapshots("examples/test12/store.json", Duration::from_secs(30));
+
+    let handler = Handler {
+        get_handler: Some(|path, query| read_entry(path, query, None)),
+        post_handler: Some(|path, query, body| create_entry(path, query, Some(body))),
+        put_handler: Some(replace_entry),
+        delete_handler: Some(delete_entry),
+        patch_handler: Some(patch_entry),
+    };
+
+    // Save once more on Ctrl+C, then return from main to exit
+    tokio::select! {
+        result = run("127.0.0.1", 8080, handler) => result,
+        result = STORE.save_on_shutdown("examples/test12/store.json") => result,
+    }
+}
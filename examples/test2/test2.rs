@@ -18,8 +18,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let handler = Handler {
         get_handler: Some(|path, query| get_index(path, query, None)),
         post_handler: Some(|path, query, body| post_submit(path, query, Some(body))),
-        put_handler: Some(|path, body| put_update(path, body)),
+        put_handler: Some(put_update),
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await?;
     Ok(())
@@ -12,6 +12,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         post_handler: Some(|path, query, body| post_submit(path, query, Some(body))),
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await?;
     Ok(())
@@ -5,28 +5,26 @@ use parfait::*;
 
 post!("/path", handle_post => r#"examples\test4\file.json"#, "application/json");
 
+// Errors become problem+json responses, or HTML error pages for browsers
+fn read_file() -> Result<String, Problem> {
+    // Read the file content
+    let content = std::fs::read_to_string("examples\\test4\\file.json")?;
+    // Parse the JSON data
+    let json_data = json_body(&content)
+        .map_err(|_| Problem::new(500).detail("The data file is not valid JSON."))?;
+    // Generate JSON response
+    let response_json = generate_json_response(json_data);
+    Ok(format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", response_json))
+}
+
 #[tokio::main]
 async fn main() -> io::Result<()> { 
     let handler = Handler {
         get_handler: None,
-        post_handler: Some(|_, _, _| {
-            // Read the file content
-            match std::fs::read_to_string("examples\\test4\\file.json") {
-                Ok(content) => {
-                    // Parse the JSON data
-                    if let Some(json_data) = parse_json(&content) {
-                        // Generate JSON response
-                        let response_json = generate_json_response(json_data);
-                        Some(format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", response_json))
-                    } else {
-                        Some("HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\nFailed to parse JSON".to_owned())
-                    }
-                },
-                Err(_) => Some("HTTP/1.1 500 INTERNAL SERVER ERROR\r\n\r\nFailed to read file".to_owned()),
-            }
-        }),
+        post_handler: Some(|_, _, _| respond(read_file())),
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await
 }
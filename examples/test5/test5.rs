@@ -21,8 +21,8 @@ async fn main() -> io::Result<()> {
                         if line.contains("{% for item in items %}") {
                             in_for_loop = true;
                             for item in &items {
-                                result.push_str(&line.replace("{% for item in items %}", &format!("{}", item)));
-                                result.push_str("\n");
+                                result.push_str(&line.replace("{% for item in items %}", item));
+                                result.push('\n');
                             }
                         } else if in_for_loop && line.contains("{% endfor %}") {
                             in_for_loop = false;
@@ -35,6 +35,7 @@ async fn main() -> io::Result<()> {
         }),
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await
 }
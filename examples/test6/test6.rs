@@ -11,6 +11,7 @@ async fn main() -> io::Result<()> {
         post_handler: None,
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
     run("127.0.0.1", 8080, handler).await
 }
@@ -19,6 +19,7 @@ async fn main() -> io::Result<()> {
         post_handler: None,
         put_handler: None,
         delete_handler: None,
+        patch_handler: None,
     };
 
     // Initialize middleware
@@ -0,0 +1,32 @@
+use std::io;
+use std::sync::Arc;
+extern crate parfait;
+use parfait::*;
+
+get!("/", home_handler => ContentType::String("Hello, world"), "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n");
+
+delete!("/admin/items", delete_item => ContentType::File("examples/test8/items.json"), "");
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    // Verify JWTs against the identity provider's key set, reloading it as keys rotate
+    let jwks = Jwks::from_file("examples/test8/jwks.json");
+    jwks.refresh()?;
+    let jwks = Arc::new(jwks);
+    let auth = Arc::new(AuthMiddleware::with_jwks(jwks));
+
+    // Anyone signed in may read, only admins holding the write scope may delete
+    let guard = Guard::new(auth)
+        .route("GET", "/")
+        .route("DELETE", "/admin").require_role("admin").require_scope("admin:write");
+
+    let handler = Handler {
+        get_handler: Some(|path, query| home_handler(path, query, None)),
+        post_handler: None,
+        put_handler: None,
+        delete_handler: Some(delete_item),
+        patch_handler: None,
+    };
+
+    run_with_middleware("127.0.0.1", 8080, handler, Arc::new(guard)).await
+}
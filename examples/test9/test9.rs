@@ -0,0 +1,30 @@
+use std::io;
+use std::sync::Arc;
+extern crate parfait;
+use parfait::*;
+
+get!("/", get_index => ContentType::File("examples/test9/index.html"), "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n");
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let handler = Handler {
+        get_handler: Some(|path, query| get_index(path, query, None)),
+        post_handler: Some(|_, _, body| {
+            // Post, redirect, then show a one-time banner on the next GET
+            let name = body.split('&').find_map(|param| param.strip_prefix("name="));
+            match name {
+                Some(name) if !name.is_empty() => flash("success", &format!("Thanks, {}!", name)),
+                _ => flash("error", "Please enter your name."),
+            }
+            Some(redirect("/"))
+        }),
+        put_handler: None,
+        delete_handler: None,
+        patch_handler: None,
+    };
+
+    let middleware = MiddlewareChain::new()
+        .with(Arc::new(SessionMiddleware::new(Arc::new(MemoryStore::new()))))
+        .with(Arc::new(CsrfMiddleware::new()));
+    run_with_middleware("127.0.0.1", 8080, handler, Arc::new(middleware)).await
+}
@@ -0,0 +1,279 @@
+use std::time::{Instant, SystemTime};
+
+use crate::{add_header, context, header, random_token, request_target, Middleware};
+
+/// Headers whose values never appear in logs.
+const SENSITIVE_HEADERS: &[&str] = &[
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+    "x-api-key",
+    "x-csrf-token",
+];
+
+/// Query parameters whose values are redacted from logged URLs by default.
+const SENSITIVE_QUERY_PARAMS: &[&str] = &[
+    "api_key",
+    "apikey",
+    "token",
+    "access_token",
+    "refresh_token",
+    "id_token",
+    "password",
+    "secret",
+    "client_secret",
+    "code",
+];
+
+/// Replaces the values of the named query parameters in a URL (or request line) with
+/// `[REDACTED]`. Names are compared case-insensitively, after percent-decoding.
+fn redact_query(target: &str, sensitive: &[String]) -> String {
+    let (path, rest) = match target.split_once('?') {
+        Some(split) => split,
+        None => return target.to_owned(),
+    };
+    // A request line carries " HTTP/1.1" after the query
+    let (query, suffix) = match rest.split_once(' ') {
+        Some((query, suffix)) => (query, format!(" {}", suffix)),
+        None => (rest, String::new()),
+    };
+    let query: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if sensitive.iter().any(|s| s.eq_ignore_ascii_case(&crate::percent_decode(name))) => {
+                format!("{}=[REDACTED]", name)
+            }
+            _ => param.to_owned(),
+        })
+        .collect();
+    format!("{}?{}{}", path, query.join("&"), suffix)
+}
+
+/// Returns the head of a raw HTTP message (start line and headers) with sensitive header
+/// values replaced by `[REDACTED]`. The body is never included.
+pub fn redact_headers(message: &str) -> String {
+    let head = message.split("\r\n\r\n").next().unwrap_or("");
+    head.lines()
+        .enumerate()
+        .map(|(i, line)| match line.split_once(':') {
+            Some((name, _))
+                if i > 0 && SENSITIVE_HEADERS.contains(&name.trim().to_ascii_lowercase().as_str()) =>
+            {
+                format!("{}: [REDACTED]", name)
+            }
+            _ => line.to_owned(),
+        })
+        .collect::<Vec<_>>()
+        .join("\r\n")
+}
+
+/// Access log line formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Common Log Format, followed by the request duration in seconds.
+    Common,
+    /// Combined Log Format (Common plus referer and user agent), followed by the duration.
+    Combined,
+    /// One JSON object per line.
+    Json,
+}
+
+/// The request ID of the current request.
+struct RequestId(String);
+
+/// What `after` needs to know about the request.
+struct AccessRecord {
+    started: Instant,
+    time: SystemTime,
+    request_line: String,
+    method: String,
+    path: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    headers: String,
+}
+
+/// Middleware that tags every request with an ID and writes one access log line per request.
+///
+/// An incoming `X-Request-Id` is kept if it looks sane, otherwise a new one is generated; either
+/// way it is echoed in the response and available to handlers through [`request_id`]. Lines go to
+/// the `log` facade at `info` level with the target `parfait::access`, so any logger
+/// implementation can route them.
+///
+/// Credentials passed in the query string, such as `api_key` or `access_token`, are redacted
+/// from logged URLs; see [`AccessLogMiddleware::redact_query_params`].
+pub struct AccessLogMiddleware {
+    format: LogFormat,
+    header_name: String,
+    log_headers: bool,
+    sensitive_query_params: Vec<String>,
+}
+
+impl AccessLogMiddleware {
+    pub fn new(format: LogFormat) -> AccessLogMiddleware {
+        AccessLogMiddleware {
+            format,
+            header_name: "X-Request-Id".to_owned(),
+            log_headers: false,
+            sensitive_query_params: SENSITIVE_QUERY_PARAMS.iter().map(|&name| name.to_owned()).collect(),
+        }
+    }
+
+    /// Uses a different header to propagate the request ID.
+    pub fn request_id_header(mut self, name: &str) -> AccessLogMiddleware {
+        self.header_name = name.to_owned();
+        self
+    }
+
+    /// Sets the query parameters whose values are replaced by `[REDACTED]` in logged URLs,
+    /// replacing the default list (`api_key`, `token`, `access_token`, `password`, ...). Include
+    /// any name given to `ApiKeyMiddleware::query_param`.
+    pub fn redact_query_params(mut self, names: &[&str]) -> AccessLogMiddleware {
+        self.sensitive_query_params = names.iter().map(|&name| name.to_owned()).collect();
+        self
+    }
+
+    /// Includes the (redacted) request headers in JSON lines.
+    pub fn log_headers(mut self, log_headers: bool) -> AccessLogMiddleware {
+        self.log_headers = log_headers;
+        self
+    }
+
+    fn line(&self, record: &AccessRecord, id: &str, response: &str) -> String {
+        let status = response.split(' ').nth(1).unwrap_or("-");
+        let bytes = response.split_once("\r\n\r\n").map_or(0, |(_, body)| body.len());
+        let duration = record.started.elapsed().as_secs_f64();
+        let peer = context::peer_addr().map(|addr| addr.ip().to_string());
+
+        match self.format {
+            LogFormat::Common | LogFormat::Combined => {
+                let mut line = format!(
+                    "{} - - [{}] \"{}\" {} {}",
+                    peer.as_deref().unwrap_or("-"),
+                    clf_time(record.time),
+                    record.request_line,
+                    status,
+                    bytes
+                );
+                if self.format == LogFormat::Combined {
+                    line.push_str(&format!(
+                        " \"{}\" \"{}\"",
+                        record.referer.as_deref().unwrap_or("-"),
+                        record.user_agent.as_deref().unwrap_or("-")
+                    ));
+                }
+                line.push_str(&format!(" {:.3}", duration));
+                line
+            }
+            LogFormat::Json => {
+                let mut entry = serde_json::json!({
+                    "time": httpdate::fmt_http_date(record.time),
+                    "request_id": id,
+                    "peer": peer,
+                    "method": record.method,
+                    "path": record.path,
+                    "status": status.parse::<u16>().ok(),
+                    "bytes": bytes,
+                    "duration_ms": duration * 1000.0,
+                    "referer": record.referer,
+                    "user_agent": record.user_agent,
+                });
+                if self.log_headers {
+                    entry["headers"] = record
+                        .headers
+                        .lines()
+                        .skip(1)
+                        .filter_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            Some((name.trim().to_owned(), serde_json::Value::from(value.trim())))
+                        })
+                        .collect::<serde_json::Map<_, _>>()
+                        .into();
+                }
+                entry.to_string()
+            }
+        }
+    }
+}
+
+impl Middleware for AccessLogMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let id = header(request, &self.header_name)
+            .filter(|id| {
+                id.len() <= 128
+                    && !id.is_empty()
+                    && id.chars().all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+            })
+            .map(str::to_owned)
+            .unwrap_or_else(|| random_token(16));
+        let (method, path, query) = request_target(request).unwrap_or(("-", "-", None));
+
+        context::insert(RequestId(id));
+        context::insert(AccessRecord {
+            started: Instant::now(),
+            time: SystemTime::now(),
+            request_line: redact_query(request.lines().next().unwrap_or(""), &self.sensitive_query_params),
+            method: method.to_owned(),
+            path: match query {
+                Some(query) => redact_query(&format!("{}?{}", path, query), &self.sensitive_query_params),
+                None => path.to_owned(),
+            },
+            referer: header(request, "Referer").map(|referer| redact_query(referer, &self.sensitive_query_params)),
+            user_agent: header(request, "User-Agent").map(str::to_owned),
+            headers: if self.log_headers {
+                redact_headers(request)
+            } else {
+                String::new()
+            },
+        });
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let record = context::remove::<AccessRecord>()?;
+        let id = request_id().unwrap_or_default();
+        log::info!(target: "parfait::access", "{}", self.line(&record, &id, response));
+        Some(add_header(response, &self.header_name, &id))
+    }
+}
+
+/// The current request's ID, if `AccessLogMiddleware` is installed.
+pub fn request_id() -> Option<String> {
+    context::with(|id: &mut RequestId| id.0.clone())
+}
+
+/// Formats a time as `10/Oct/2000:13:55:36 +0000`.
+fn clf_time(time: SystemTime) -> String {
+    // Rearranges the IMF-fixdate "Tue, 10 Oct 2000 13:55:36 GMT"
+    let date = httpdate::fmt_http_date(time);
+    let parts: Vec<&str> = date.split(' ').collect();
+    match parts.as_slice() {
+        [_, day, month, year, clock, _] => format!("{}/{}/{}:{} +0000", day, month, year, clock),
+        _ => date,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn defaults() -> Vec<String> {
+        SENSITIVE_QUERY_PARAMS.iter().map(|&name| name.to_owned()).collect()
+    }
+
+    #[test]
+    fn redacts_credentials_in_request_lines() {
+        assert_eq!(
+            redact_query("GET /items?page=2&api_key=s3cret&Access%5Ftoken=t HTTP/1.1", &defaults()),
+            "GET /items?page=2&api_key=[REDACTED]&Access%5Ftoken=[REDACTED] HTTP/1.1"
+        );
+    }
+
+    #[test]
+    fn leaves_other_urls_alone() {
+        assert_eq!(redact_query("/items?page=2", &defaults()), "/items?page=2");
+        assert_eq!(redact_query("GET /items HTTP/1.1", &defaults()), "GET /items HTTP/1.1");
+        assert_eq!(redact_query("/x?token", &defaults()), "/x?token");
+    }
+}
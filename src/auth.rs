@@ -0,0 +1,362 @@
+use std::collections::HashMap;
+use std::io;
+
+use base64::Engine;
+use serde_json::Value;
+
+use crate::{header, Middleware, Problem};
+
+/// The authenticated caller of a request, as established by an authentication middleware.
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    pub subject: Option<String>,
+    pub claims: Value,
+}
+
+impl Identity {
+    /// Creates an identity for `subject` with no other claims.
+    pub fn new(subject: &str) -> Identity {
+        Identity::from_claims(serde_json::json!({ "sub": subject }))
+    }
+
+    /// Adds a claim, e.g. `with_claim("roles", json!(["admin"]))`.
+    pub fn with_claim(mut self, name: &str, value: Value) -> Identity {
+        if !self.claims.is_object() {
+            self.claims = Value::Object(Default::default());
+        }
+        self.claims[name] = value;
+        self
+    }
+
+    /// Builds an identity from a JWT claims object, taking the subject from `sub`.
+    pub fn from_claims(claims: Value) -> Identity {
+        Identity {
+            subject: claims.get("sub").and_then(Value::as_str).map(str::to_owned),
+            claims,
+        }
+    }
+
+    /// Roles granted by the `roles` claim (an array or a space separated string).
+    pub fn roles(&self) -> Vec<&str> {
+        claim_list(&self.claims, &["roles", "role"])
+    }
+
+    /// Scopes granted by the `scope` claim (space separated) or the `scp` array.
+    pub fn scopes(&self) -> Vec<&str> {
+        claim_list(&self.claims, &["scope", "scp"])
+    }
+
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles().contains(&role)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes().contains(&scope)
+    }
+}
+
+fn claim_list<'a>(claims: &'a Value, names: &[&str]) -> Vec<&'a str> {
+    names
+        .iter()
+        .filter_map(|name| claims.get(*name))
+        .flat_map(|value| match value {
+            Value::String(s) => s.split_whitespace().collect(),
+            Value::Array(items) => items.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        })
+        .collect()
+}
+
+/// Something that can establish who sent a request.
+pub trait Authenticator: Send + Sync {
+    fn authenticate(&self, request: &str) -> Option<Identity>;
+}
+
+/// Compares two byte strings in time that depends only on their lengths.
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Checks a username and password pair for HTTP Basic authentication.
+pub trait CredentialVerifier: Send + Sync {
+    fn verify(&self, username: &str, password: &str) -> Option<Identity>;
+}
+
+impl<F> CredentialVerifier for F
+where
+    F: Fn(&str, &str) -> Option<Identity> + Send + Sync,
+{
+    fn verify(&self, username: &str, password: &str) -> Option<Identity> {
+        self(username, password)
+    }
+}
+
+/// Plain-text username/password pairs, compared in constant time.
+pub struct StaticCredentials {
+    users: HashMap<String, String>,
+}
+
+impl StaticCredentials {
+    pub fn new(users: HashMap<String, String>) -> StaticCredentials {
+        StaticCredentials { users }
+    }
+}
+
+impl CredentialVerifier for StaticCredentials {
+    fn verify(&self, username: &str, password: &str) -> Option<Identity> {
+        match self.users.get(username) {
+            Some(expected) if constant_time_eq(expected.as_bytes(), password.as_bytes()) => Some(Identity::new(username)),
+            Some(_) => None,
+            None => {
+                // Spend the same time on unknown users so timing does not reveal who exists
+                std::hint::black_box(constant_time_eq(password.as_bytes(), password.as_bytes()));
+                None
+            }
+        }
+    }
+}
+
+/// Users loaded from an htpasswd-style file of `username:hash` lines.
+///
+/// Hashes may be bcrypt (`$2a$`, `$2b$`, `$2y$`) or argon2 (`$argon2id$`, ...) in PHC format.
+/// Blank lines and lines starting with `#` are ignored.
+pub struct PasswordFile {
+    users: HashMap<String, String>,
+    /// A hash checked for unknown users, so they cost as much as a wrong password.
+    dummy: Option<String>,
+}
+
+impl PasswordFile {
+    pub fn load(path: &str) -> io::Result<PasswordFile> {
+        let content = std::fs::read_to_string(path)?;
+        let users = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| line.split_once(':'))
+            .map(|(user, hash)| (user.to_owned(), hash.to_owned()))
+            .collect::<HashMap<String, String>>();
+        let dummy = users.values().next().cloned();
+        Ok(PasswordFile { users, dummy })
+    }
+}
+
+fn verify_hash(username: &str, password: &str, hash: &str) -> bool {
+    if hash.starts_with("$2") {
+        bcrypt::verify(password, hash).unwrap_or(false)
+    } else if hash.starts_with("$argon2") {
+        use argon2::PasswordVerifier;
+        match argon2::PasswordHash::new(hash) {
+            Ok(parsed) => argon2::Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    } else {
+        error!("Unsupported password hash for user '{}'", username);
+        false
+    }
+}
+
+impl CredentialVerifier for PasswordFile {
+    fn verify(&self, username: &str, password: &str) -> Option<Identity> {
+        let hash = match self.users.get(username) {
+            Some(hash) => hash,
+            None => {
+                // Run a real hash check anyway so timing does not reveal which users exist
+                if let Some(dummy) = &self.dummy {
+                    verify_hash(username, password, dummy);
+                }
+                return None;
+            }
+        };
+        if verify_hash(username, password, hash) {
+            Some(Identity::new(username))
+        } else {
+            None
+        }
+    }
+}
+
+/// HTTP Basic authentication middleware.
+pub struct BasicAuthMiddleware {
+    realm: String,
+    verifier: Box<dyn CredentialVerifier>,
+}
+
+impl BasicAuthMiddleware {
+    pub fn new(realm: &str, verifier: impl CredentialVerifier + 'static) -> BasicAuthMiddleware {
+        BasicAuthMiddleware {
+            realm: realm.to_owned(),
+            verifier: Box::new(verifier),
+        }
+    }
+}
+
+impl Authenticator for BasicAuthMiddleware {
+    fn authenticate(&self, request: &str) -> Option<Identity> {
+        let value = header(request, "Authorization")?;
+        let (scheme, encoded) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") {
+            return None;
+        }
+        let decoded = base64::engine::general_purpose::STANDARD.decode(encoded.trim()).ok()?;
+        let decoded = String::from_utf8(decoded).ok()?;
+        let (username, password) = decoded.split_once(':')?;
+        self.verifier.verify(username, password)
+    }
+}
+
+impl Middleware for BasicAuthMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        match self.authenticate(request) {
+            Some(_) => None,
+            None => Some(
+                Problem::new(401)
+                    .header("WWW-Authenticate", &format!("Basic realm=\"{}\", charset=\"UTF-8\"", self.realm))
+                    .response(),
+            ),
+        }
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+}
+
+/// API-key authentication middleware.
+///
+/// The key is read from the `X-API-Key` header by default, or from a query parameter if one is
+/// configured with [`ApiKeyMiddleware::query_param`]. Each key maps to the identity it authenticates.
+pub struct ApiKeyMiddleware {
+    keys: Vec<(String, Identity)>,
+    header: String,
+    query_param: Option<String>,
+}
+
+impl ApiKeyMiddleware {
+    pub fn new(keys: HashMap<String, Identity>) -> ApiKeyMiddleware {
+        ApiKeyMiddleware {
+            keys: keys.into_iter().collect(),
+            header: "X-API-Key".to_owned(),
+            query_param: None,
+        }
+    }
+
+    /// Reads the key from a different header.
+    pub fn header(mut self, name: &str) -> ApiKeyMiddleware {
+        self.header = name.to_owned();
+        self
+    }
+
+    /// Also accepts the key as a query parameter, e.g. `?api_key=...`.
+    pub fn query_param(mut self, name: &str) -> ApiKeyMiddleware {
+        self.query_param = Some(name.to_owned());
+        self
+    }
+
+    fn presented_key<'a>(&self, request: &'a str) -> Option<&'a str> {
+        if let Some(key) = header(request, &self.header) {
+            return Some(key);
+        }
+        let name = self.query_param.as_deref()?;
+        let (_, _, query) = crate::request_target(request)?;
+        query?.split('&').find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            if key == name {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Authenticator for ApiKeyMiddleware {
+    fn authenticate(&self, request: &str) -> Option<Identity> {
+        let presented = self.presented_key(request)?;
+        // Check every key in constant time so timing reveals nothing about the stored keys
+        let mut found = None;
+        for (key, identity) in &self.keys {
+            if constant_time_eq(key.as_bytes(), presented.as_bytes()) {
+                found = Some(identity.clone());
+            }
+        }
+        found
+    }
+}
+
+impl Middleware for ApiKeyMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        match self.authenticate(request) {
+            Some(_) => None,
+            None => Some(Problem::new(401).detail("A valid API key is required.").response()),
+        }
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn basic(credentials: &str) -> String {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(credentials);
+        format!("GET / HTTP/1.1\r\nAuthorization: Basic {}\r\n\r\n", encoded)
+    }
+
+    fn basic_auth() -> BasicAuthMiddleware {
+        let users = HashMap::from([("ann".to_owned(), "s3cret".to_owned())]);
+        BasicAuthMiddleware::new("admin", StaticCredentials::new(users))
+    }
+
+    #[test]
+    fn basic_auth_checks_credentials() {
+        let auth = basic_auth();
+        assert_eq!(auth.authenticate(&basic("ann:s3cret")).unwrap().subject.as_deref(), Some("ann"));
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nauthorization: basic YW5uOnMzY3JldA==\r\n\r\n").is_some());
+        assert!(auth.authenticate(&basic("ann:wrong")).is_none());
+        assert!(auth.authenticate(&basic("bob:s3cret")).is_none());
+        assert!(auth.authenticate(&basic("ann")).is_none());
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nAuthorization: Basic !!not-base64!!\r\n\r\n").is_none());
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nAuthorization: Bearer YW5uOnMzY3JldA==\r\n\r\n").is_none());
+
+        let challenge = auth.before(&basic("ann:wrong")).unwrap();
+        assert!(challenge.starts_with("HTTP/1.1 401 UNAUTHORIZED\r\n"));
+        assert!(challenge.contains("\r\nWWW-Authenticate: Basic realm=\"admin\", charset=\"UTF-8\"\r\n"));
+        assert_eq!(auth.before(&basic("ann:s3cret")), None);
+    }
+
+    #[test]
+    fn password_files_verify_hashes() {
+        let path = std::env::temp_dir().join(format!("parfait-htpasswd-{}", std::process::id()));
+        let hash = bcrypt::hash("s3cret", 4).unwrap();
+        std::fs::write(&path, format!("# users\n\nann:{}\n", hash)).unwrap();
+        let users = PasswordFile::load(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(users.verify("ann", "s3cret").is_some());
+        assert!(users.verify("ann", "wrong").is_none());
+        assert!(users.verify("bob", "s3cret").is_none());
+    }
+
+    #[test]
+    fn api_keys_come_from_the_header_or_query() {
+        let keys = HashMap::from([("k3y".to_owned(), Identity::new("service"))]);
+        let auth = ApiKeyMiddleware::new(keys).query_param("api_key");
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nx-api-key: k3y\r\n\r\n").is_some());
+        assert!(auth.authenticate("GET /items?page=2&api_key=k3y HTTP/1.1\r\n\r\n").is_some());
+        assert!(auth.authenticate("GET /items?api_key=nope HTTP/1.1\r\n\r\n").is_none());
+        assert!(auth.authenticate("GET /items?key=k3y HTTP/1.1\r\n\r\n").is_none());
+        assert!(auth.before("GET / HTTP/1.1\r\n\r\n").unwrap().starts_with("HTTP/1.1 401"));
+
+        let header_only = ApiKeyMiddleware::new(HashMap::from([("k3y".to_owned(), Identity::new("service"))]));
+        assert!(header_only.authenticate("GET /?api_key=k3y HTTP/1.1\r\n\r\n").is_none());
+    }
+}
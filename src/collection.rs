@@ -0,0 +1,505 @@
+use std::cmp::Ordering;
+
+use base64::Engine;
+use serde_json::{Map, Value};
+
+use crate::{percent_decode, Problem};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    In,
+    Contains,
+}
+
+impl Operator {
+    fn parse(name: &str) -> Option<Operator> {
+        Some(match name {
+            "eq" => Operator::Eq,
+            "ne" => Operator::Ne,
+            "gt" => Operator::Gt,
+            "gte" => Operator::Gte,
+            "lt" => Operator::Lt,
+            "lte" => Operator::Lte,
+            "in" => Operator::In,
+            "contains" => Operator::Contains,
+            _ => return None,
+        })
+    }
+}
+
+struct Filter {
+    field: String,
+    operator: Operator,
+    value: String,
+}
+
+/// Looks up a dotted field path such as `address.city`.
+fn field<'v>(item: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').try_fold(item, |value, key| value.get(key))
+}
+
+/// Copies the dotted `fields` of `item` into a new object, keeping their nesting.
+fn select(item: &Value, fields: &[String]) -> Value {
+    let mut selected = Value::Object(Map::new());
+    for name in fields {
+        let value = match field(item, name) {
+            Some(value) => value.clone(),
+            None => continue,
+        };
+        let mut target = &mut selected;
+        let mut keys = name.split('.').peekable();
+        while let Some(key) = keys.next() {
+            let object = match target {
+                Value::Object(object) => object,
+                // An earlier field selected the whole parent, which already includes this one
+                _ => break,
+            };
+            if keys.peek().is_none() {
+                object.insert(key.to_owned(), value);
+                break;
+            }
+            target = object.entry(key.to_owned()).or_insert_with(|| Value::Object(Map::new()));
+        }
+    }
+    selected
+}
+
+/// Compares a JSON value with a query string value, numerically when both are numbers.
+fn compare_to(value: &Value, text: &str) -> Option<Ordering> {
+    match value {
+        Value::Number(number) => number.as_f64()?.partial_cmp(&text.parse::<f64>().ok()?),
+        Value::String(string) => Some(string.as_str().cmp(text)),
+        Value::Bool(boolean) => Some(boolean.to_string().as_str().cmp(text)),
+        Value::Null => Some("null".cmp(text)),
+        _ => None,
+    }
+}
+
+/// Orders two JSON values for sorting; missing values and `null` sort last.
+fn compare_values(a: Option<&Value>, b: Option<&Value>) -> Ordering {
+    match (a, b) {
+        (None | Some(Value::Null), None | Some(Value::Null)) => Ordering::Equal,
+        (None | Some(Value::Null), _) => Ordering::Greater,
+        (_, None | Some(Value::Null)) => Ordering::Less,
+        (Some(Value::Number(a)), Some(Value::Number(b))) => {
+            a.as_f64().partial_cmp(&b.as_f64()).unwrap_or(Ordering::Equal)
+        }
+        (Some(Value::String(a)), Some(Value::String(b))) => a.cmp(b),
+        (Some(Value::Bool(a)), Some(Value::Bool(b))) => a.cmp(b),
+        (Some(a), Some(b)) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+impl Filter {
+    fn matches(&self, item: &Value) -> bool {
+        let value = match field(item, &self.field) {
+            Some(value) => value,
+            None => return self.operator == Operator::Ne,
+        };
+        match self.operator {
+            Operator::Eq => compare_to(value, &self.value) == Some(Ordering::Equal),
+            Operator::Ne => compare_to(value, &self.value) != Some(Ordering::Equal),
+            Operator::Gt => compare_to(value, &self.value) == Some(Ordering::Greater),
+            Operator::Gte => matches!(compare_to(value, &self.value), Some(Ordering::Greater | Ordering::Equal)),
+            Operator::Lt => compare_to(value, &self.value) == Some(Ordering::Less),
+            Operator::Lte => matches!(compare_to(value, &self.value), Some(Ordering::Less | Ordering::Equal)),
+            Operator::In => self
+                .value
+                .split(',')
+                .any(|option| compare_to(value, option) == Some(Ordering::Equal)),
+            Operator::Contains => match value {
+                Value::String(string) => string.contains(&self.value),
+                Value::Array(items) => items.iter().any(|item| compare_to(item, &self.value) == Some(Ordering::Equal)),
+                _ => false,
+            },
+        }
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    text.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b',' | b'[' | b']' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn encode_cursor(id: &Value) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(id.to_string())
+}
+
+fn decode_cursor(cursor: &str) -> Option<Value> {
+    let bytes = base64::engine::general_purpose::URL_SAFE_NO_PAD.decode(cursor).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+/// One page of a filtered and sorted collection.
+#[derive(Debug)]
+pub struct Page {
+    /// The items on this page, reduced to the selected fields.
+    pub items: Vec<Value>,
+    /// How many items matched the filters, across all pages.
+    pub total: usize,
+    /// The position of the first item on this page among all matching items.
+    pub offset: usize,
+    /// The page size, or `None` when every matching item is returned.
+    pub limit: Option<usize>,
+    /// The cursor for the following page, when paging by cursor and there is one.
+    pub next_cursor: Option<String>,
+}
+
+/// Query-string driven filtering, sorting, field selection and pagination of a JSON array.
+///
+/// | Parameter               | Meaning                                                      |
+/// |-------------------------|--------------------------------------------------------------|
+/// | `status=active`         | keep items whose `status` equals `active`                    |
+/// | `age[gte]=18`           | compare with `eq`, `ne`, `gt`, `gte`, `lt`, `lte`            |
+/// | `role[in]=admin,editor` | keep items whose `role` is one of the listed values          |
+/// | `tags[contains]=rust`   | keep items whose string or array `tags` contains `rust`      |
+/// | `sort=-created,name`    | sort by `created` descending, then `name` ascending          |
+/// | `fields=id,name`        | return only these fields of each item                        |
+/// | `limit=20&offset=40`    | return 20 items starting at the 41st                         |
+/// | `limit=20&cursor=`      | return the first 20 items, with a cursor for the next page   |
+/// | `limit=20&cursor=...`   | return 20 items following the cursor of the previous page    |
+///
+/// Field names may be dotted (`address.city`) to reach nested values, in filters, `sort` and
+/// `fields` alike; selected nested fields keep their nesting. Numbers compare numerically.
+/// Cursors name the last item of the previous page by its `"id"`, so pages stay consistent
+/// while items are added or removed before them.
+pub struct CollectionQuery {
+    params: Vec<(String, String)>,
+    filters: Vec<Filter>,
+    sort: Vec<(String, bool)>,
+    fields: Option<Vec<String>>,
+    limit: Option<usize>,
+    offset: usize,
+    /// `Some(None)` when paging by cursor from the start.
+    cursor: Option<Option<Value>>,
+    default_limit: Option<usize>,
+    max_limit: usize,
+}
+
+impl CollectionQuery {
+    /// Parses a query string, returning a message describing the first invalid parameter.
+    pub fn parse(query: Option<&str>) -> Result<CollectionQuery, String> {
+        let params: Vec<(String, String)> = query
+            .unwrap_or("")
+            .split('&')
+            .filter(|param| !param.is_empty())
+            .map(|param| {
+                let (key, value) = param.split_once('=').unwrap_or((param, ""));
+                (percent_decode(key), percent_decode(value))
+            })
+            .collect();
+
+        let mut parsed = CollectionQuery {
+            params: params.clone(),
+            filters: Vec::new(),
+            sort: Vec::new(),
+            fields: None,
+            limit: None,
+            offset: 0,
+            cursor: None,
+            default_limit: None,
+            max_limit: 1000,
+        };
+        let number = |key: &str, value: &str| {
+            value
+                .parse::<usize>()
+                .map_err(|_| format!("'{}' must be a non-negative integer", key))
+        };
+
+        for (key, value) in params {
+            match key.as_str() {
+                "sort" => {
+                    parsed.sort = value
+                        .split(',')
+                        // `+` arrives decoded as a space
+                        .map(str::trim)
+                        .filter(|field| !field.is_empty())
+                        .map(|field| match field.strip_prefix('-') {
+                            Some(field) => (field.to_owned(), true),
+                            None => (field.trim_start_matches('+').to_owned(), false),
+                        })
+                        .collect();
+                }
+                "fields" => parsed.fields = Some(value.split(',').filter(|f| !f.is_empty()).map(str::to_owned).collect()),
+                "limit" => parsed.limit = Some(number("limit", &value)?),
+                "offset" => parsed.offset = number("offset", &value)?,
+                "cursor" if value.is_empty() => parsed.cursor = Some(None),
+                "cursor" => parsed.cursor = Some(Some(decode_cursor(&value).ok_or("'cursor' is not a valid cursor")?)),
+                _ => {
+                    let (field, operator) = match key.strip_suffix(']').and_then(|key| key.split_once('[')) {
+                        Some((field, operator)) => (
+                            field,
+                            Operator::parse(operator).ok_or_else(|| format!("unknown filter operator '{}'", operator))?,
+                        ),
+                        None => (key.as_str(), Operator::Eq),
+                    };
+                    parsed.filters.push(Filter {
+                        field: field.to_owned(),
+                        operator,
+                        value,
+                    });
+                }
+            }
+        }
+        if parsed.cursor.is_some() && parsed.offset > 0 {
+            return Err("'cursor' and 'offset' cannot be combined".to_owned());
+        }
+        Ok(parsed)
+    }
+
+    /// Page size used when the query has no `limit`. By default every item is returned.
+    pub fn default_limit(mut self, limit: usize) -> CollectionQuery {
+        self.default_limit = Some(limit);
+        self
+    }
+
+    /// Largest page size a client may ask for. Defaults to 1000.
+    pub fn max_limit(mut self, limit: usize) -> CollectionQuery {
+        self.max_limit = limit;
+        self
+    }
+
+    fn effective_limit(&self) -> Option<usize> {
+        match self.limit.or(self.default_limit) {
+            Some(limit) => Some(limit.min(self.max_limit)),
+            // A cursor only makes sense with pages, so fall back to the largest one
+            None if self.cursor.is_some() => Some(self.max_limit),
+            None => None,
+        }
+    }
+
+    /// Filters, sorts and pages `items`, returning the requested page.
+    pub fn apply(&self, items: Vec<Value>) -> Result<Page, String> {
+        let mut items: Vec<Value> = items
+            .into_iter()
+            .filter(|item| self.filters.iter().all(|filter| filter.matches(item)))
+            .collect();
+        if !self.sort.is_empty() {
+            // Stable, so items equal on every sort key keep their stored order
+            items.sort_by(|a, b| {
+                self.sort.iter().fold(Ordering::Equal, |ordering, (name, descending)| {
+                    ordering.then_with(|| {
+                        let ordering = compare_values(field(a, name), field(b, name));
+                        if *descending {
+                            ordering.reverse()
+                        } else {
+                            ordering
+                        }
+                    })
+                })
+            });
+        }
+
+        let total = items.len();
+        let limit = self.effective_limit();
+        if self.cursor.is_some() && limit == Some(0) {
+            return Err("'limit' must be positive when paging by cursor".to_owned());
+        }
+        let offset = match &self.cursor {
+            Some(None) => 0,
+            Some(Some(after)) => {
+                let position = items.iter().position(|item| item.get("id") == Some(after));
+                position.map(|i| i + 1).ok_or("the item named by 'cursor' no longer matches")?
+            }
+            None => self.offset.min(total),
+        };
+        let end = limit.map_or(total, |limit| (offset + limit).min(total));
+
+        let next_cursor = match (limit, &self.cursor) {
+            (Some(_), Some(_)) if offset < end && end < total => items[end - 1].get("id").map(encode_cursor),
+            _ => None,
+        };
+        let items = items
+            .drain(offset..end)
+            .map(|item| match &self.fields {
+                Some(fields) => select(&item, fields),
+                None => item,
+            })
+            .collect();
+        Ok(Page {
+            items,
+            total,
+            offset,
+            limit,
+            next_cursor,
+        })
+    }
+
+    /// The URL of `path` with this query, replacing the pagination parameters.
+    fn link(&self, path: &str, pagination: &[(&str, String)]) -> String {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .filter(|(key, _)| key != "offset" && key != "cursor")
+            .map(|(key, value)| (key.as_str(), value.clone()))
+            .chain(pagination.iter().cloned())
+            .map(|(key, value)| format!("{}={}", percent_encode(key), percent_encode(&value)))
+            .collect();
+        if params.is_empty() {
+            path.to_owned()
+        } else {
+            format!("{}?{}", path, params.join("&"))
+        }
+    }
+
+    /// Builds a `200` JSON response for `items` served at `path`, with an `X-Total-Count` header
+    /// and, when paginated, a `Link` header pointing at the neighbouring pages.
+    pub fn response(&self, path: &str, items: Vec<Value>) -> String {
+        let page = match self.apply(items) {
+            Ok(page) => page,
+            Err(message) => return bad_request(&message),
+        };
+
+        let mut links = Vec::new();
+        if let Some(limit) = page.limit {
+            if self.cursor.is_some() {
+                links.push(format!("<{}>; rel=\"first\"", self.link(path, &[("cursor", String::new())])));
+                if let Some(cursor) = &page.next_cursor {
+                    links.push(format!("<{}>; rel=\"next\"", self.link(path, &[("cursor", cursor.clone())])));
+                }
+            } else if limit > 0 {
+                let offset_link = |offset: usize| self.link(path, &[("offset", offset.to_string())]);
+                links.push(format!("<{}>; rel=\"first\"", offset_link(0)));
+                if page.offset > 0 {
+                    links.push(format!("<{}>; rel=\"prev\"", offset_link(page.offset.saturating_sub(limit))));
+                }
+                if page.offset + limit < page.total {
+                    links.push(format!("<{}>; rel=\"next\"", offset_link(page.offset + limit)));
+                }
+                let last = page.total.saturating_sub(1) / limit * limit;
+                links.push(format!("<{}>; rel=\"last\"", offset_link(last)));
+            }
+        }
+
+        let mut response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nX-Total-Count: {}\r\n",
+            page.total
+        );
+        if !links.is_empty() {
+            response.push_str(&format!("Link: {}\r\n", links.join(", ")));
+        }
+        response.push_str("\r\n");
+        response.push_str(&serde_json::to_string(&page.items).unwrap_or_default());
+        response
+    }
+}
+
+fn bad_request(message: &str) -> String {
+    Problem::new(400).detail(message).response()
+}
+
+/// Serves a JSON array at `path`, filtered, sorted and paginated according to `query`.
+///
+/// Use it from any handler that returns a collection; see `CollectionQuery` for the supported
+/// parameters. An invalid parameter gives a `400` problem.
+pub fn json_collection_response(path: &str, query: Option<&str>, items: Vec<Value>) -> String {
+    match CollectionQuery::parse(query) {
+        Ok(query) => query.response(path, items),
+        Err(message) => bad_request(&message),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn users() -> Vec<Value> {
+        vec![
+            json!({ "id": 1, "name": "ann", "age": 31, "role": "admin", "address": { "city": "Oslo", "zip": "0150" } }),
+            json!({ "id": 2, "name": "bob", "age": 17, "role": "editor", "tags": ["rust"] }),
+            json!({ "id": 3, "name": "cid", "age": 45, "role": "viewer", "address": { "city": "Rome" } }),
+            json!({ "id": 4, "name": "dee", "age": 31, "role": "editor", "tags": ["go", "rust"] }),
+        ]
+    }
+
+    fn page(query: &str) -> Result<Page, String> {
+        CollectionQuery::parse(Some(query))?.apply(users())
+    }
+
+    fn ids(page: &Page) -> Vec<i64> {
+        page.items.iter().map(|item| item["id"].as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn filters_with_operators() {
+        assert_eq!(ids(&page("role=editor").unwrap()), vec![2, 4]);
+        assert_eq!(ids(&page("age[gte]=31&age[lt]=45").unwrap()), vec![1, 4]);
+        assert_eq!(ids(&page("role[in]=admin,viewer").unwrap()), vec![1, 3]);
+        assert_eq!(ids(&page("tags[contains]=rust&name[ne]=bob").unwrap()), vec![4]);
+        assert_eq!(ids(&page("address.city=Rome").unwrap()), vec![3]);
+        assert_eq!(ids(&page("name=a%20b").unwrap()), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn rejects_invalid_parameters() {
+        assert!(CollectionQuery::parse(Some("age[between]=1")).is_err());
+        assert!(CollectionQuery::parse(Some("limit=-1")).is_err());
+        assert!(CollectionQuery::parse(Some("cursor=!!")).is_err());
+        assert!(CollectionQuery::parse(Some("cursor=&offset=2")).is_err());
+    }
+
+    #[test]
+    fn sorts_by_several_fields() {
+        assert_eq!(ids(&page("sort=-age,name").unwrap()), vec![3, 1, 4, 2]);
+        // `+name` arrives as " name" once decoded
+        assert_eq!(ids(&page("sort=age,+name").unwrap()), vec![2, 1, 4, 3]);
+        assert_eq!(ids(&page("sort=address.city").unwrap()), vec![1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn selects_top_level_and_dotted_fields() {
+        let page = page("fields=id,address.city&id=1").unwrap();
+        assert_eq!(page.items, vec![json!({ "id": 1, "address": { "city": "Oslo" } })]);
+        let page = self::page("fields=address,address.city&id=1").unwrap();
+        assert_eq!(page.items, vec![json!({ "address": { "city": "Oslo", "zip": "0150" } })]);
+    }
+
+    #[test]
+    fn pages_by_offset() {
+        let first = page("limit=3").unwrap();
+        assert_eq!((ids(&first), first.total, first.offset), (vec![1, 2, 3], 4, 0));
+        assert_eq!(ids(&page("limit=3&offset=3").unwrap()), vec![4]);
+        assert_eq!(ids(&page("offset=10").unwrap()), Vec::<i64>::new());
+        assert_eq!(ids(&page("limit=0").unwrap()), Vec::<i64>::new());
+    }
+
+    #[test]
+    fn pages_by_cursor() {
+        let first = page("limit=3&cursor=").unwrap();
+        assert_eq!(ids(&first), vec![1, 2, 3]);
+        let cursor = first.next_cursor.unwrap();
+        let second = page(&format!("limit=3&cursor={}", cursor)).unwrap();
+        assert_eq!((ids(&second), second.next_cursor), (vec![4], None));
+    }
+
+    #[test]
+    fn cursor_with_zero_limit_is_an_error() {
+        assert!(page("limit=0&cursor=").is_err());
+        let response = json_collection_response("/items", Some("limit=0&cursor="), users());
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+
+    #[test]
+    fn links_neighbouring_pages() {
+        let query = CollectionQuery::parse(Some("role=editor&limit=1&offset=1")).unwrap();
+        let response = query.response("/users", users());
+        assert!(response.contains("X-Total-Count: 2\r\n"));
+        assert!(response.contains(
+            "Link: </users?role=editor&limit=1&offset=0>; rel=\"first\", </users?role=editor&limit=1&offset=0>; rel=\"prev\", </users?role=editor&limit=1&offset=1>; rel=\"last\"\r\n"
+        ));
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::Write;
+
+use crate::{header, Middleware};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the best supported encoding from an `Accept-Encoding` header, honouring q-values.
+/// Ties go to brotli, then gzip, then deflate.
+fn negotiate(accept: &str) -> Option<Encoding> {
+    let mut wildcard = None;
+    let mut explicit: Vec<(&str, f32)> = Vec::new();
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let name = parts.next().unwrap_or("").trim();
+        let q = parts
+            .find_map(|param| param.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse::<f32>().ok())
+            .unwrap_or(1.0);
+        if name == "*" {
+            wildcard = Some(q);
+        } else {
+            explicit.push((name, q));
+        }
+    }
+
+    let mut best: Option<(Encoding, f32)> = None;
+    for encoding in [Encoding::Brotli, Encoding::Gzip, Encoding::Deflate] {
+        let q = explicit
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(encoding.name()))
+            .map(|(_, q)| *q)
+            .or(wildcard)
+            .unwrap_or(0.0);
+        if q > 0.0 && best.is_none_or(|(_, best_q)| q > best_q) {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+fn compress(encoding: Encoding, data: &[u8]) -> std::io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Deflate => {
+            // HTTP "deflate" means the zlib format
+            let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut output = Vec::new();
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+                encoder.write_all(data)?;
+            }
+            Ok(output)
+        }
+    }
+}
+
+/// Decodes a `Transfer-Encoding: chunked` body, or returns `None` if it is malformed.
+fn dechunk(mut body: &[u8]) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    loop {
+        let line_end = body.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&body[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Some(data);
+        }
+        data.extend_from_slice(body.get(..size)?);
+        body = body.get(size + 2..)?;
+    }
+}
+
+/// Response compression middleware (brotli, gzip and deflate).
+///
+/// Compresses response bodies when the client accepts one of the encodings, the content type is
+/// textual (`text/*`, JSON, JavaScript, XML, SVG, ...) and the body is at least `min_size` bytes.
+/// Responses that already have a `Content-Encoding` are left alone, so pre-compressed media and
+/// images pass through. Chunked (streamed) responses are compressed and re-sent as one chunk.
+pub struct CompressionMiddleware {
+    min_size: usize,
+    content_types: Vec<String>,
+}
+
+impl Default for CompressionMiddleware {
+    fn default() -> Self {
+        CompressionMiddleware::new()
+    }
+}
+
+impl CompressionMiddleware {
+    pub fn new() -> CompressionMiddleware {
+        let content_types = [
+            "text/",
+            "application/json",
+            "application/javascript",
+            "application/xml",
+            "application/problem+json",
+            "image/svg+xml",
+        ];
+        CompressionMiddleware {
+            min_size: 1024,
+            content_types: content_types.iter().map(|t| t.to_string()).collect(),
+        }
+    }
+
+    /// Leaves bodies smaller than this uncompressed.
+    pub fn min_size(mut self, min_size: usize) -> CompressionMiddleware {
+        self.min_size = min_size;
+        self
+    }
+
+    /// Also compresses this content type (or every type starting with it, if it ends in `/`).
+    pub fn content_type(mut self, content_type: &str) -> CompressionMiddleware {
+        self.content_types.push(content_type.to_ascii_lowercase());
+        self
+    }
+
+    fn eligible(&self, content_type: &str) -> bool {
+        let content_type = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+        content_type.ends_with("+json")
+            || content_type.ends_with("+xml")
+            || self.content_types.iter().any(|t| {
+                if t.ends_with('/') {
+                    content_type.starts_with(t.as_str())
+                } else {
+                    content_type == *t
+                }
+            })
+    }
+}
+
+impl Middleware for CompressionMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+
+    fn encode(&self, request: &str, response: Vec<u8>) -> Vec<u8> {
+        let split = match response.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(split) => split,
+            None => return response,
+        };
+        let head = match std::str::from_utf8(&response[..split]) {
+            Ok(head) => head,
+            Err(_) => return response,
+        };
+        let body = &response[split + 4..];
+
+        let eligible = header(head, "Content-Encoding").is_none()
+            && header(head, "Content-Type").is_some_and(|t| self.eligible(t));
+        if !eligible {
+            return response;
+        }
+
+        let chunked = header(head, "Transfer-Encoding").is_some_and(|t| t.eq_ignore_ascii_case("chunked"));
+        let payload = if chunked {
+            match dechunk(body) {
+                Some(payload) => payload,
+                None => return response,
+            }
+        } else {
+            body.to_vec()
+        };
+
+        let mut lines: Vec<String> = head.split("\r\n").map(str::to_owned).collect();
+        lines.push("Vary: Accept-Encoding".to_owned());
+
+        let encoding = header(request, "Accept-Encoding").and_then(negotiate);
+        let compressed = match encoding {
+            Some(encoding) if payload.len() >= self.min_size => {
+                compress(encoding, &payload).ok().map(|data| (encoding, data))
+            }
+            _ => None,
+        };
+        let (encoding, data) = match compressed {
+            Some(compressed) => compressed,
+            None => {
+                let mut output = lines.join("\r\n").into_bytes();
+                output.extend_from_slice(b"\r\n\r\n");
+                output.extend_from_slice(body);
+                return output;
+            }
+        };
+
+        // The length changes, so drop any stale Content-Length before adding the new one
+        lines.retain(|line| {
+            let name = line.split(':').next().unwrap_or("").trim();
+            !name.eq_ignore_ascii_case("Content-Length")
+        });
+        lines.push(format!("Content-Encoding: {}", encoding.name()));
+
+        let mut output;
+        if chunked {
+            output = lines.join("\r\n").into_bytes();
+            output.extend_from_slice(format!("\r\n\r\n{:x}\r\n", data.len()).as_bytes());
+            output.extend_from_slice(&data);
+            output.extend_from_slice(b"\r\n0\r\n\r\n");
+        } else {
+            lines.push(format!("Content-Length: {}", data.len()));
+            output = lines.join("\r\n").into_bytes();
+            output.extend_from_slice(b"\r\n\r\n");
+            output.extend_from_slice(&data);
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn negotiates_by_q_value() {
+        assert_eq!(negotiate("gzip, deflate, br"), Some(Encoding::Brotli));
+        assert_eq!(negotiate("gzip;q=1.0, br;q=0.5"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("deflate, gzip;q=0.8"), Some(Encoding::Deflate));
+        assert_eq!(negotiate("GZIP"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("*;q=0.5, br;q=0"), Some(Encoding::Gzip));
+        assert_eq!(negotiate("identity"), None);
+        assert_eq!(negotiate("gzip;q=0, *;q=0"), None);
+        assert_eq!(negotiate(""), None);
+    }
+
+    #[test]
+    fn dechunks_bodies() {
+        assert_eq!(dechunk(b"4\r\nWiki\r\n5;ext=1\r\npedia\r\n0\r\n\r\n"), Some(b"Wikipedia".to_vec()));
+        assert_eq!(dechunk(b"4\r\nWi"), None);
+        assert_eq!(dechunk(b"zz\r\n"), None);
+    }
+
+    #[test]
+    fn compresses_eligible_responses() {
+        let middleware = CompressionMiddleware::new().min_size(10);
+        let body = "{\"message\": \"hello hello hello hello\"}";
+        let response = format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        let request = "GET / HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\n";
+
+        let encoded = middleware.encode(request, response.clone().into_bytes());
+        let split = encoded.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let head = std::str::from_utf8(&encoded[..split]).unwrap();
+        assert_eq!(header(head, "Content-Encoding"), Some("gzip"));
+        assert_eq!(header(head, "Vary"), Some("Accept-Encoding"));
+        let compressed = &encoded[split + 4..];
+        assert_eq!(header(head, "Content-Length"), Some(compressed.len().to_string().as_str()));
+        let mut decoded = String::new();
+        flate2::read::GzDecoder::new(compressed).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, body);
+
+        // Images and small bodies pass through
+        let image = "HTTP/1.1 200 OK\r\nContent-Type: image/png\r\n\r\n0123456789abcdef";
+        assert_eq!(middleware.encode(request, image.as_bytes().to_vec()), image.as_bytes());
+        let small = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nhi";
+        assert!(!String::from_utf8(middleware.encode(request, small.as_bytes().to_vec())).unwrap().contains("Content-Encoding"));
+    }
+}
@@ -0,0 +1,60 @@
+//! Per-request state shared between middleware and handlers.
+//!
+//! While a request is processed by `handle_client_with_middleware`, middleware can store values
+//! here in `before`, handlers can read or change them, and middleware can pick them up again in
+//! `after`. The state is dropped once the response has been produced.
+
+use std::any::{Any, TypeId};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+thread_local! {
+    static CONTEXT: RefCell<Option<HashMap<TypeId, Box<dyn Any>>>> = const { RefCell::new(None) };
+}
+
+/// The address of the client that sent the current request.
+#[derive(Clone, Copy, Debug)]
+struct PeerAddr(SocketAddr);
+
+/// Runs `f` with a fresh request context for a request from `peer`.
+pub(crate) fn scope<R>(peer: Option<SocketAddr>, f: impl FnOnce() -> R) -> R {
+    let previous = CONTEXT.with(|c| c.borrow_mut().replace(HashMap::new()));
+    if let Some(peer) = peer {
+        insert(PeerAddr(peer));
+    }
+    let result = f();
+    CONTEXT.with(|c| *c.borrow_mut() = previous);
+    result
+}
+
+/// Stores a value for the rest of the current request, replacing any value of the same type.
+pub fn insert<T: Any>(value: T) {
+    CONTEXT.with(|c| {
+        if let Some(map) = c.borrow_mut().as_mut() {
+            map.insert(TypeId::of::<T>(), Box::new(value));
+        }
+    })
+}
+
+/// Calls `f` with the stored value of type `T`, if the current request has one.
+pub fn with<T: Any, R>(f: impl FnOnce(&mut T) -> R) -> Option<R> {
+    // Take the value out while `f` runs so it may use the context itself
+    let mut value = remove::<T>()?;
+    let result = f(&mut value);
+    insert(value);
+    Some(result)
+}
+
+/// Removes and returns the stored value of type `T`.
+pub fn remove<T: Any>() -> Option<T> {
+    CONTEXT.with(|c| {
+        let value = c.borrow_mut().as_mut()?.remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    })
+}
+
+/// The address of the client that sent the current request.
+pub fn peer_addr() -> Option<SocketAddr> {
+    with(|peer: &mut PeerAddr| peer.0)
+}
@@ -0,0 +1,305 @@
+use std::fmt;
+use std::time::{Duration, SystemTime};
+
+use crate::add_header;
+
+/// The `SameSite` attribute of a cookie.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// A cookie to send to the client in a `Set-Cookie` header.
+///
+/// ```text
+/// let cookie = Cookie::new("theme", "dark").path("/").max_age(Duration::from_secs(3600)).http_only(true);
+/// let response = add_header(&response, "Set-Cookie", &cookie.to_string());
+/// ```
+#[derive(Clone, Debug)]
+pub struct Cookie {
+    name: String,
+    value: String,
+    path: Option<String>,
+    domain: Option<String>,
+    max_age: Option<Duration>,
+    expires: Option<SystemTime>,
+    secure: bool,
+    http_only: bool,
+    same_site: Option<SameSite>,
+}
+
+impl Cookie {
+    pub fn new(name: &str, value: &str) -> Cookie {
+        Cookie {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            path: None,
+            domain: None,
+            max_age: None,
+            expires: None,
+            secure: false,
+            http_only: false,
+            same_site: None,
+        }
+    }
+
+    /// Builds a cookie that tells the client to delete `name` immediately.
+    ///
+    /// Browsers only remove a cookie whose `Path` and `Domain` match the original, so set those
+    /// on the result if the cookie was created with them.
+    pub fn removal(name: &str) -> Cookie {
+        Cookie::new(name, "")
+            .max_age(Duration::ZERO)
+            .expires(SystemTime::UNIX_EPOCH)
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Replaces the value, keeping every attribute.
+    pub(crate) fn value_of(mut self, value: String) -> Cookie {
+        self.value = value;
+        self
+    }
+
+    pub fn path(mut self, path: &str) -> Cookie {
+        self.path = Some(path.to_owned());
+        self
+    }
+
+    pub fn domain(mut self, domain: &str) -> Cookie {
+        self.domain = Some(domain.to_owned());
+        self
+    }
+
+    pub fn max_age(mut self, max_age: Duration) -> Cookie {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn expires(mut self, expires: SystemTime) -> Cookie {
+        self.expires = Some(expires);
+        self
+    }
+
+    pub fn secure(mut self, secure: bool) -> Cookie {
+        self.secure = secure;
+        self
+    }
+
+    pub fn http_only(mut self, http_only: bool) -> Cookie {
+        self.http_only = http_only;
+        self
+    }
+
+    pub fn same_site(mut self, same_site: SameSite) -> Cookie {
+        self.same_site = Some(same_site);
+        self
+    }
+}
+
+/// Whether `byte` may appear in a cookie name, an RFC 6265 token. `%` is reserved for escapes.
+fn is_token(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"()<>@,;:\\\"/[]?={}%".contains(&byte)
+}
+
+/// Whether `byte` may appear unencoded in a cookie value. `%` is reserved for escapes.
+fn is_cookie_octet(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b"\",;\\%".contains(&byte)
+}
+
+/// Whether `byte` may appear in a `Path` or `Domain` attribute.
+fn is_attribute_octet(byte: u8) -> bool {
+    byte.is_ascii_graphic() && !b";,%".contains(&byte)
+}
+
+/// Percent-encodes every byte `allowed` rejects, so no text can break out of its header.
+fn encode(text: &str, allowed: fn(u8) -> bool) -> String {
+    text.bytes()
+        .map(|byte| {
+            if allowed(byte) {
+                (byte as char).to_string()
+            } else {
+                format!("%{:02X}", byte)
+            }
+        })
+        .collect()
+}
+
+/// Undoes `encode`. Unlike query strings, `+` stays a `+`.
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+            Some(byte) if bytes[i] == b'%' => {
+                decoded.push(byte);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Formats the cookie as the value of a `Set-Cookie` header.
+///
+/// Characters a cookie cannot carry, such as control characters, whitespace, `;` and `,`, are
+/// percent-encoded; `CookieJar` decodes them again when the cookie comes back.
+impl fmt::Display for Cookie {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", encode(&self.name, is_token), encode(&self.value, is_cookie_octet))?;
+        if let Some(path) = &self.path {
+            write!(f, "; Path={}", encode(path, is_attribute_octet))?;
+        }
+        if let Some(domain) = &self.domain {
+            write!(f, "; Domain={}", encode(domain, is_attribute_octet))?;
+        }
+        if let Some(max_age) = self.max_age {
+            write!(f, "; Max-Age={}", max_age.as_secs())?;
+        }
+        if let Some(expires) = self.expires {
+            write!(f, "; Expires={}", httpdate::fmt_http_date(expires))?;
+        }
+        if self.secure {
+            write!(f, "; Secure")?;
+        }
+        if self.http_only {
+            write!(f, "; HttpOnly")?;
+        }
+        match self.same_site {
+            Some(SameSite::Strict) => write!(f, "; SameSite=Strict")?,
+            Some(SameSite::Lax) => write!(f, "; SameSite=Lax")?,
+            Some(SameSite::None) => write!(f, "; SameSite=None")?,
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+/// The cookies sent with a request, plus any cookies to set or remove in the response.
+#[derive(Clone, Debug, Default)]
+pub struct CookieJar {
+    incoming: Vec<(String, String)>,
+    outgoing: Vec<Cookie>,
+}
+
+impl CookieJar {
+    /// Parses every `Cookie` header of a raw HTTP request.
+    pub fn from_request(request: &str) -> CookieJar {
+        let incoming = request
+            .lines()
+            .skip(1)
+            .take_while(|line| !line.is_empty())
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case("Cookie"))
+            .flat_map(|(_, value)| value.split(';'))
+            .filter_map(|pair| {
+                // Only the first '=' separates name and value; values may contain more
+                let (name, value) = pair.trim().split_once('=')?;
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                Some((decode(name.trim()), decode(value)))
+            })
+            .collect();
+        CookieJar {
+            incoming,
+            outgoing: Vec::new(),
+        }
+    }
+
+    /// Returns the value of a cookie, preferring one set during this request.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        if let Some(cookie) = self.outgoing.iter().rev().find(|c| c.name == name) {
+            // A pending removal hides the request's value
+            return if cookie.max_age == Some(Duration::ZERO) {
+                None
+            } else {
+                Some(&cookie.value)
+            };
+        }
+        self.incoming
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Iterates over the `(name, value)` pairs sent with the request.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.incoming.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    /// Queues a cookie to be sent with the response.
+    pub fn add(&mut self, cookie: Cookie) {
+        self.outgoing.push(cookie);
+    }
+
+    /// Queues the removal of a cookie set on `path`.
+    pub fn remove(&mut self, name: &str, path: &str) {
+        self.outgoing.push(Cookie::removal(name).path(path));
+    }
+
+    /// Cookies queued for the response.
+    pub fn delta(&self) -> &[Cookie] {
+        &self.outgoing
+    }
+
+    /// Adds one `Set-Cookie` header per queued cookie to a raw HTTP response.
+    pub fn apply(&self, response: &str) -> String {
+        // Headers are inserted after the status line, so go backwards to keep their order
+        self.outgoing
+            .iter()
+            .rev()
+            .fold(response.to_owned(), |response, cookie| {
+                add_header(&response, "Set-Cookie", &cookie.to_string())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_cookie_cannot_inject_headers() {
+        let cookie = Cookie::new("a", "x\r\nX-Injected: 1").path("/;\r\nX: 1");
+        let header = cookie.to_string();
+        assert_eq!(header, "a=x%0D%0AX-Injected:%201; Path=/%3B%0D%0AX:%201");
+    }
+
+    #[test]
+    fn invalid_name_characters_are_encoded() {
+        assert_eq!(Cookie::new("a b=c", "v").to_string(), "a%20b%3Dc=v");
+    }
+
+    #[test]
+    fn encoded_values_round_trip() {
+        let value = "50% off; \"quoted\", +plus";
+        let header = Cookie::new("deal", value).to_string();
+        let jar = CookieJar::from_request(&format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", header));
+        assert_eq!(jar.get("deal"), Some(value));
+    }
+
+    #[test]
+    fn parses_cookie_headers() {
+        let request = "GET / HTTP/1.1\r\nCookie: a=1; b=\"two\"\r\ncookie: c=x=y\r\n\r\nd=4";
+        let jar = CookieJar::from_request(request);
+        assert_eq!(jar.iter().collect::<Vec<_>>(), vec![("a", "1"), ("b", "two"), ("c", "x=y")]);
+        assert_eq!(jar.get("d"), None);
+    }
+}
@@ -0,0 +1,289 @@
+use std::time::Duration;
+
+use crate::{add_header, context, header, request_target, Middleware};
+
+enum OriginRule {
+    Any,
+    Exact(String),
+    Wildcard(String, String),
+    Predicate(Box<dyn Fn(&str) -> bool + Send + Sync>),
+}
+
+impl OriginRule {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginRule::Any => true,
+            OriginRule::Exact(allowed) => allowed == origin,
+            OriginRule::Wildcard(prefix, suffix) => {
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+            }
+            OriginRule::Predicate(f) => f(origin),
+        }
+    }
+}
+
+/// The allowed origin of the current request, remembered for `after`.
+struct CorsOrigin(String);
+
+/// Cross-Origin Resource Sharing middleware.
+///
+/// Answers preflight `OPTIONS` requests itself and adds the `Access-Control-*` headers to
+/// responses for allowed origins. Put it first in a `MiddlewareChain` so preflights are not
+/// rejected by authentication, which browsers never send with them.
+pub struct CorsMiddleware {
+    origins: Vec<OriginRule>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    exposed_headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+}
+
+impl Default for CorsMiddleware {
+    fn default() -> Self {
+        CorsMiddleware::new()
+    }
+}
+
+impl CorsMiddleware {
+    /// Creates a policy that allows no origins yet, with the common methods allowed and
+    /// requested headers mirrored back.
+    pub fn new() -> CorsMiddleware {
+        CorsMiddleware {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"].iter().map(|m| m.to_string()).collect(),
+            headers: Vec::new(),
+            exposed_headers: Vec::new(),
+            credentials: false,
+            max_age: None,
+        }
+    }
+
+    /// Allows an origin such as `"https://app.example.com"`. A single `*` may stand for any
+    /// subdomain part, as in `"https://*.example.com"`.
+    pub fn allow_origin(mut self, origin: &str) -> CorsMiddleware {
+        let origin = origin.trim_end_matches('/');
+        self.origins.push(match origin.split_once('*') {
+            Some((prefix, suffix)) => OriginRule::Wildcard(prefix.to_owned(), suffix.to_owned()),
+            None => OriginRule::Exact(origin.to_owned()),
+        });
+        self
+    }
+
+    /// Allows every origin.
+    pub fn allow_any_origin(mut self) -> CorsMiddleware {
+        self.origins.push(OriginRule::Any);
+        self
+    }
+
+    /// Allows origins for which `predicate` returns `true`.
+    pub fn allow_origin_fn(mut self, predicate: impl Fn(&str) -> bool + Send + Sync + 'static) -> CorsMiddleware {
+        self.origins.push(OriginRule::Predicate(Box::new(predicate)));
+        self
+    }
+
+    /// Replaces the allowed methods.
+    pub fn allow_methods(mut self, methods: &[&str]) -> CorsMiddleware {
+        self.methods = methods.iter().map(|m| m.to_ascii_uppercase()).collect();
+        self
+    }
+
+    /// Allows only these request headers instead of mirroring whatever the preflight asks for.
+    pub fn allow_headers(mut self, headers: &[&str]) -> CorsMiddleware {
+        self.headers = headers.iter().map(|h| h.to_ascii_lowercase()).collect();
+        self
+    }
+
+    /// Response headers scripts on the other origin may read.
+    pub fn expose_headers(mut self, headers: &[&str]) -> CorsMiddleware {
+        self.exposed_headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Allows cookies and HTTP authentication on cross-origin requests.
+    pub fn allow_credentials(mut self, credentials: bool) -> CorsMiddleware {
+        self.credentials = credentials;
+        self
+    }
+
+    /// How long browsers may cache a preflight result.
+    pub fn max_age(mut self, max_age: Duration) -> CorsMiddleware {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    fn is_allowed(&self, origin: &str) -> bool {
+        self.origins.iter().any(|rule| rule.matches(origin))
+    }
+
+    /// `*` is only valid for anonymous requests; everything else echoes the origin back.
+    fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        let any = self.origins.iter().any(|rule| matches!(rule, OriginRule::Any));
+        if any && !self.credentials {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    fn preflight(&self, request: &str, origin: &str) -> String {
+        let forbidden = "HTTP/1.1 403 Forbidden\r\nVary: Origin\r\nContent-Length: 0\r\n\r\n".to_owned();
+        if !self.is_allowed(origin) {
+            return forbidden;
+        }
+        let method = header(request, "Access-Control-Request-Method").unwrap_or("");
+        if !self.methods.iter().any(|m| m.eq_ignore_ascii_case(method)) {
+            return forbidden;
+        }
+        let requested = header(request, "Access-Control-Request-Headers").unwrap_or("");
+        let allowed_headers = if self.headers.is_empty() {
+            requested.to_owned()
+        } else {
+            let all_allowed = requested
+                .split(',')
+                .map(|h| h.trim().to_ascii_lowercase())
+                .filter(|h| !h.is_empty())
+                .all(|h| self.headers.contains(&h));
+            if !all_allowed {
+                return forbidden;
+            }
+            self.headers.join(", ")
+        };
+
+        let mut response = format!(
+            "HTTP/1.1 204 No Content\r\nAccess-Control-Allow-Origin: {}\r\nAccess-Control-Allow-Methods: {}\r\n",
+            self.allow_origin_value(origin),
+            self.methods.join(", ")
+        );
+        if !allowed_headers.is_empty() {
+            response.push_str(&format!("Access-Control-Allow-Headers: {}\r\n", allowed_headers));
+        }
+        if self.credentials {
+            response.push_str("Access-Control-Allow-Credentials: true\r\n");
+        }
+        if let Some(max_age) = self.max_age {
+            response.push_str(&format!("Access-Control-Max-Age: {}\r\n", max_age.as_secs()));
+        }
+        response.push_str("Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers\r\nContent-Length: 0\r\n\r\n");
+        response
+    }
+}
+
+impl Middleware for CorsMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, _, _) = request_target(request)?;
+        let origin = header(request, "Origin")?;
+
+        if method == "OPTIONS" && header(request, "Access-Control-Request-Method").is_some() {
+            return Some(self.preflight(request, origin));
+        }
+        if self.is_allowed(origin) {
+            context::insert(CorsOrigin(origin.to_owned()));
+        }
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let origin = match context::remove::<CorsOrigin>() {
+            Some(CorsOrigin(origin)) => origin,
+            // The answer still depends on the Origin header, so caches must key on it
+            None if self.allow_origin_value("") == "*" => return None,
+            None => return Some(add_header(response, "Vary", "Origin")),
+        };
+
+        let allow_origin = self.allow_origin_value(&origin);
+        let mut response = add_header(response, "Access-Control-Allow-Origin", allow_origin);
+        if allow_origin != "*" {
+            response = add_header(&response, "Vary", "Origin");
+        }
+        if self.credentials {
+            response = add_header(&response, "Access-Control-Allow-Credentials", "true");
+        }
+        if !self.exposed_headers.is_empty() {
+            response = add_header(&response, "Access-Control-Expose-Headers", &self.exposed_headers.join(", "));
+        }
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OK: &str = "HTTP/1.1 200 OK\r\n\r\n";
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> String {
+        format!(
+            "OPTIONS /items HTTP/1.1\r\nOrigin: {}\r\nAccess-Control-Request-Method: {}\r\nAccess-Control-Request-Headers: {}\r\n\r\n",
+            origin, method, headers
+        )
+    }
+
+    /// Runs a request through `cors` and a handler answering `200 OK`.
+    fn respond(cors: &CorsMiddleware, request: &str) -> String {
+        context::scope(None, || match cors.before(request) {
+            Some(response) => response,
+            None => cors.after(OK).unwrap_or_else(|| OK.to_owned()),
+        })
+    }
+
+    #[test]
+    fn answers_preflights_for_allowed_origins() {
+        let cors = CorsMiddleware::new()
+            .allow_origin("https://app.example.com/")
+            .allow_headers(&["Content-Type", "X-CSRF-Token"])
+            .max_age(Duration::from_secs(600));
+        let response = respond(&cors, &preflight("https://app.example.com", "PUT", "content-type, x-csrf-token"));
+        assert!(response.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example.com\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Methods: GET, HEAD, POST, PUT, PATCH, DELETE\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Headers: content-type, x-csrf-token\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Max-Age: 600\r\n"));
+        assert!(response.contains("\r\nVary: Origin, "));
+
+        let forbidden = respond(&cors, &preflight("https://app.example.com", "PUT", "x-other"));
+        assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+        let forbidden = respond(&cors, &preflight("https://app.example.com", "TRACE", ""));
+        assert!(forbidden.starts_with("HTTP/1.1 403 Forbidden\r\n"));
+    }
+
+    #[test]
+    fn disallowed_origins_get_no_access_headers() {
+        let cors = CorsMiddleware::new().allow_origin("https://*.example.com");
+        let preflight = respond(&cors, &preflight("https://evil.test", "GET", ""));
+        assert!(preflight.starts_with("HTTP/1.1 403 Forbidden\r\nVary: Origin\r\n"));
+
+        let response = respond(&cors, "GET /items HTTP/1.1\r\nOrigin: https://example.com.evil.test\r\n\r\n");
+        assert!(!response.contains("Access-Control-Allow-Origin"));
+        assert!(response.contains("\r\nVary: Origin\r\n"));
+
+        let response = respond(&cors, "GET /items HTTP/1.1\r\nOrigin: https://app.example.com\r\n\r\n");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://app.example.com\r\n"));
+        assert!(response.contains("\r\nVary: Origin\r\n"));
+    }
+
+    #[test]
+    fn credentials_never_get_a_wildcard_origin() {
+        let anonymous = CorsMiddleware::new().allow_any_origin();
+        let response = respond(&anonymous, "GET / HTTP/1.1\r\nOrigin: https://a.test\r\n\r\n");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: *\r\n"));
+        assert!(!response.contains("Vary"));
+
+        let credentialed = CorsMiddleware::new().allow_any_origin().allow_credentials(true);
+        let response = respond(&credentialed, "GET / HTTP/1.1\r\nOrigin: https://a.test\r\n\r\n");
+        assert!(response.contains("\r\nAccess-Control-Allow-Origin: https://a.test\r\n"));
+        assert!(response.contains("\r\nAccess-Control-Allow-Credentials: true\r\n"));
+        assert!(response.contains("\r\nVary: Origin\r\n"));
+        let preflight = respond(&credentialed, &preflight("https://a.test", "POST", ""));
+        assert!(preflight.contains("\r\nAccess-Control-Allow-Origin: https://a.test\r\n"));
+        assert!(!preflight.contains("Access-Control-Allow-Origin: *"));
+    }
+
+    #[test]
+    fn same_origin_requests_pass_through() {
+        let cors = CorsMiddleware::new().allow_origin("https://app.example.com");
+        assert_eq!(respond(&cors, "GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"), "HTTP/1.1 200 OK\r\nVary: Origin\r\n\r\n");
+    }
+}
@@ -0,0 +1,244 @@
+use crate::auth::constant_time_eq;
+use crate::{context, escape_html, header, random_token, request_target, Cookie, CookieJar, Middleware, Problem, SameSite};
+
+/// Random bytes in a token; encoded, a token is `TOKEN_LENGTH` URL-safe base64 characters.
+const TOKEN_BYTES: usize = 32;
+const TOKEN_LENGTH: usize = 43;
+
+/// Whether a token from a cookie looks like one `random_token` issued.
+fn well_formed(token: &str) -> bool {
+    token.len() == TOKEN_LENGTH && token.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// The CSRF token of the current request.
+struct CsrfToken {
+    value: String,
+    issued: bool,
+}
+
+/// Cross-site request forgery protection using the double-submit cookie pattern.
+///
+/// Every client gets a random token in a cookie. Requests with an unsafe method (`POST`, `PUT`,
+/// `PATCH`, `DELETE`) must echo that token in a `csrf_token` form field or an `X-CSRF-Token`
+/// header, and their `Origin` (or `Referer`) must be the server itself or an allowed origin.
+/// Failing requests get `403 Forbidden`.
+///
+/// Requests that carry an `Authorization: Bearer` header are exempt, since browsers never attach
+/// bearer tokens on their own.
+pub struct CsrfMiddleware {
+    cookie_name: String,
+    secure: bool,
+    allowed_origins: Vec<String>,
+    exempt_paths: Vec<String>,
+    exempt_bearer: bool,
+}
+
+impl Default for CsrfMiddleware {
+    fn default() -> Self {
+        CsrfMiddleware::new()
+    }
+}
+
+impl CsrfMiddleware {
+    pub fn new() -> CsrfMiddleware {
+        CsrfMiddleware {
+            cookie_name: "parfait_csrf".to_owned(),
+            secure: false,
+            allowed_origins: Vec::new(),
+            exempt_paths: Vec::new(),
+            exempt_bearer: true,
+        }
+    }
+
+    /// Marks the token cookie `Secure`; enable this when served over HTTPS.
+    pub fn secure(mut self, secure: bool) -> CsrfMiddleware {
+        self.secure = secure;
+        self
+    }
+
+    /// Accepts form submissions from another origin, e.g. `"https://app.example.com"`.
+    pub fn allow_origin(mut self, origin: &str) -> CsrfMiddleware {
+        self.allowed_origins.push(origin.trim_end_matches('/').to_owned());
+        self
+    }
+
+    /// Skips the checks for paths starting with `path`, e.g. webhook receivers.
+    pub fn exempt(mut self, path: &str) -> CsrfMiddleware {
+        self.exempt_paths.push(path.to_owned());
+        self
+    }
+
+    /// Whether requests with an `Authorization: Bearer` header skip the checks (default `true`).
+    pub fn exempt_bearer(mut self, exempt: bool) -> CsrfMiddleware {
+        self.exempt_bearer = exempt;
+        self
+    }
+
+    fn origin_allowed(&self, request: &str) -> bool {
+        let source = match header(request, "Origin").or_else(|| header(request, "Referer")) {
+            Some(source) => source,
+            // Neither header is sent by some privacy tools; the token check still applies
+            None => return true,
+        };
+        let origin = match source.find("://") {
+            Some(i) => match source[i + 3..].find('/') {
+                Some(j) => &source[..i + 3 + j],
+                None => source,
+            },
+            None => return false,
+        };
+        let host = origin.split_once("://").map(|(_, host)| host);
+        (host.is_some() && host == header(request, "Host"))
+            || self.allowed_origins.iter().any(|allowed| allowed == origin)
+    }
+
+    fn submitted_token<'a>(&self, request: &'a str) -> Option<&'a str> {
+        if let Some(token) = header(request, "X-CSRF-Token") {
+            return Some(token);
+        }
+        let (_, body) = request.split_once("\r\n\r\n")?;
+        body.trim().split('&').find_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            if key == "csrf_token" {
+                Some(value)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Middleware for CsrfMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, path, _) = request_target(request)?;
+
+        let existing = CookieJar::from_request(request)
+            .get(&self.cookie_name)
+            // Cookies can be planted from sibling subdomains; anything else gets a fresh token
+            .filter(|token| well_formed(token))
+            .map(str::to_owned);
+        let token = CsrfToken {
+            issued: existing.is_none(),
+            value: existing.unwrap_or_else(|| random_token(TOKEN_BYTES)),
+        };
+
+        let safe = matches!(method, "GET" | "HEAD" | "OPTIONS" | "TRACE");
+        let bearer = header(request, "Authorization")
+            .is_some_and(|value| value.get(..7).is_some_and(|scheme| scheme.eq_ignore_ascii_case("Bearer ")));
+        let exempt = (self.exempt_bearer && bearer) || self.exempt_paths.iter().any(|p| path.starts_with(p.as_str()));
+
+        if !safe && !exempt {
+            let valid = !token.issued
+                && self
+                    .submitted_token(request)
+                    .is_some_and(|submitted| constant_time_eq(submitted.as_bytes(), token.value.as_bytes()));
+            if !valid || !self.origin_allowed(request) {
+                return Some(Problem::new(403).detail("CSRF check failed.").response());
+            }
+        }
+
+        context::insert(token);
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let token = context::remove::<CsrfToken>()?;
+        if !token.issued {
+            return None;
+        }
+        let mut jar = CookieJar::default();
+        jar.add(
+            Cookie::new(&self.cookie_name, &token.value)
+                .path("/")
+                .http_only(true)
+                .secure(self.secure)
+                .same_site(SameSite::Strict),
+        );
+        Some(jar.apply(response))
+    }
+}
+
+/// The current request's CSRF token, if `CsrfMiddleware` is installed.
+pub fn csrf_token() -> Option<String> {
+    context::with(|token: &mut CsrfToken| token.value.clone())
+}
+
+/// A hidden form field carrying the CSRF token, to place inside every `<form>`.
+pub fn csrf_field() -> String {
+    match csrf_token() {
+        Some(token) => format!("<input type=\"hidden\" name=\"csrf_token\" value=\"{}\">", escape_html(&token)),
+        None => String::new(),
+    }
+}
+
+/// Replaces `{{ csrf_field }}` in a template with [`csrf_field`].
+pub fn render_csrf(template: &str) -> String {
+    if template.contains("{{ csrf_field }}") {
+        template.replace("{{ csrf_field }}", &csrf_field())
+    } else {
+        template.to_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "abcdefghijklmnopqrstuvwxyz0123456789-_ABCDE";
+
+    fn post(headers: &str, body: &str) -> String {
+        format!(
+            "POST /form HTTP/1.1\r\nHost: example.com\r\nCookie: parfait_csrf={}\r\n{}\r\n{}",
+            TOKEN, headers, body
+        )
+    }
+
+    fn check(csrf: &CsrfMiddleware, request: &str) -> Option<String> {
+        context::scope(None, || csrf.before(request))
+    }
+
+    #[test]
+    fn hostile_cookie_tokens_are_replaced() {
+        let csrf = CsrfMiddleware::new();
+        let request = "GET / HTTP/1.1\r\nCookie: parfait_csrf=\"><script>alert(1)</script>\r\n\r\n";
+        context::scope(None, || {
+            assert_eq!(csrf.before(request), None);
+            let field = csrf_field();
+            assert!(!field.contains("<script>"));
+            let token = csrf_token().unwrap();
+            assert!(well_formed(&token));
+            assert_eq!(field, format!("<input type=\"hidden\" name=\"csrf_token\" value=\"{}\">", token));
+            let response = csrf.after("HTTP/1.1 200 OK\r\n\r\n").unwrap();
+            assert!(response.contains(&format!("Set-Cookie: parfait_csrf={};", token)));
+        });
+    }
+
+    #[test]
+    fn unsafe_requests_need_the_cookie_token() {
+        let csrf = CsrfMiddleware::new();
+        let missing = check(&csrf, &post("", "name=a")).unwrap();
+        assert!(missing.starts_with("HTTP/1.1 403"));
+        assert!(check(&csrf, &post("", "name=a&csrf_token=wrong")).is_some());
+        assert!(check(&csrf, &post("", &format!("name=a&csrf_token={}", TOKEN))).is_none());
+        assert!(check(&csrf, &post(&format!("X-CSRF-Token: {}\r\n", TOKEN), "")).is_none());
+        assert!(check(&csrf, "GET / HTTP/1.1\r\n\r\n").is_none());
+    }
+
+    #[test]
+    fn foreign_origins_are_rejected() {
+        let csrf = CsrfMiddleware::new().allow_origin("https://app.example.com/");
+        let body = format!("csrf_token={}", TOKEN);
+        assert!(check(&csrf, &post("Origin: https://evil.example\r\n", &body)).is_some());
+        assert!(check(&csrf, &post("Referer: https://evil.example/form\r\n", &body)).is_some());
+        assert!(check(&csrf, &post("Origin: http://example.com\r\n", &body)).is_none());
+        assert!(check(&csrf, &post("Origin: https://app.example.com\r\n", &body)).is_none());
+    }
+
+    #[test]
+    fn bearer_requests_are_exempt() {
+        let csrf = CsrfMiddleware::new();
+        assert!(check(&csrf, &post("Authorization: bearer t0k3n\r\n", "")).is_none());
+        // A multi-byte character at the scheme boundary must not panic
+        assert!(check(&csrf, &post("Authorization: abcdef\u{20ac}x\r\n", "")).is_some());
+    }
+}
@@ -0,0 +1,132 @@
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::Problem;
+
+static MAX_BODY_SIZE: AtomicUsize = AtomicUsize::new(1024 * 1024);
+
+/// Sets the largest request body the server accepts, in bytes, both as sent and after
+/// decompression. Larger bodies are rejected with `413 Payload Too Large`. Defaults to 1 MiB.
+pub fn set_max_body_size(bytes: usize) {
+    MAX_BODY_SIZE.store(bytes, Ordering::Relaxed);
+}
+
+pub(crate) fn max_body_size() -> usize {
+    MAX_BODY_SIZE.load(Ordering::Relaxed)
+}
+
+pub(crate) fn payload_too_large() -> Problem {
+    Problem::new(413).detail("Request body too large.").header("Connection", "close")
+}
+
+fn unsupported(encoding: &str) -> Problem {
+    Problem::new(415)
+        .detail(&format!("Unsupported Content-Encoding '{}'.", encoding))
+        .header("Accept-Encoding", "gzip, deflate, br")
+}
+
+/// Reads at most `limit` decoded bytes, or fails with `None` if the stream holds more.
+fn read_limited(reader: impl Read, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    reader.take(limit as u64 + 1).read_to_end(&mut data)?;
+    Ok(if data.len() > limit { None } else { Some(data) })
+}
+
+/// Undoes the `Content-Encoding` of a request body.
+///
+/// Encodings are removed in the reverse of the order they were applied. Fails with the problem
+/// to report when an encoding is unknown, the data is corrupt, or the decompressed body exceeds
+/// the size cap.
+pub(crate) fn decode_body(content_encoding: &str, body: Vec<u8>) -> Result<Vec<u8>, Problem> {
+    let limit = max_body_size();
+    let mut body = body;
+    for encoding in content_encoding.split(',').rev() {
+        let encoding = encoding.trim().to_ascii_lowercase();
+        let decoded = match encoding.as_str() {
+            "" | "identity" => continue,
+            "gzip" | "x-gzip" => read_limited(flate2::read::MultiGzDecoder::new(&body[..]), limit),
+            // Accept raw deflate too, as some clients send it despite the spec asking for zlib
+            "deflate" if body.first().is_some_and(|b| b & 0x0f == 8) => {
+                read_limited(flate2::read::ZlibDecoder::new(&body[..]), limit)
+            }
+            "deflate" => read_limited(flate2::read::DeflateDecoder::new(&body[..]), limit),
+            "br" => read_limited(brotli::Decompressor::new(&body[..], 4096), limit),
+            _ => return Err(unsupported(&encoding)),
+        };
+        body = match decoded {
+            Ok(Some(decoded)) => decoded,
+            Ok(None) => return Err(payload_too_large()),
+            Err(_) => return Err(Problem::new(400).detail(&format!("Malformed {} request body.", encoding))),
+        };
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    const TEXT: &[u8] = b"{\"name\": \"parfait\", \"layers\": [\"fruit\", \"yogurt\", \"granola\"]}";
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn raw_deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn brotli(data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        {
+            let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 5, 22);
+            encoder.write_all(data).unwrap();
+        }
+        output
+    }
+
+    #[test]
+    fn decodes_each_encoding() {
+        assert_eq!(decode_body("gzip", gzip(TEXT)).unwrap(), TEXT);
+        assert_eq!(decode_body("X-GZIP", gzip(TEXT)).unwrap(), TEXT);
+        assert_eq!(decode_body("br", brotli(TEXT)).unwrap(), TEXT);
+        assert_eq!(decode_body("identity", TEXT.to_vec()).unwrap(), TEXT);
+        assert_eq!(decode_body("deflate, gzip", gzip(&zlib(TEXT))).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn accepts_zlib_and_raw_deflate() {
+        let zlib = zlib(TEXT);
+        let raw = raw_deflate(TEXT);
+        assert_eq!(zlib[0] & 0x0f, 8);
+        assert_ne!(raw[0] & 0x0f, 8);
+        assert_eq!(decode_body("deflate", zlib).unwrap(), TEXT);
+        assert_eq!(decode_body("deflate", raw).unwrap(), TEXT);
+    }
+
+    #[test]
+    fn caps_the_decompressed_size() {
+        let bomb = gzip(&vec![0; max_body_size() + 1]);
+        assert!(bomb.len() < max_body_size());
+        assert_eq!(decode_body("gzip", bomb).unwrap_err().status(), 413);
+    }
+
+    #[test]
+    fn rejects_unknown_and_corrupt_bodies() {
+        let problem = decode_body("compress", TEXT.to_vec()).unwrap_err();
+        assert_eq!(problem.status(), 415);
+        assert!(problem.render(None).contains("Accept-Encoding: gzip, deflate, br\r\n"));
+        assert_eq!(decode_body("gzip", TEXT.to_vec()).unwrap_err().status(), 400);
+    }
+}
@@ -0,0 +1,268 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use serde_json::Value;
+
+/// One lock per file, shared by every `JsonFile` in the process that points at it.
+fn process_lock(path: &Path) -> Arc<Mutex<()>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>> = OnceLock::new();
+    let key = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    LOCKS
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_default()
+        .clone()
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Flushes a rename to disk by syncing the directory that holds the file.
+fn sync_parent(path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// A JSON document stored in a file, updated atomically.
+///
+/// Every update holds a lock for the file, so concurrent requests modifying the same file are
+/// applied one after another instead of overwriting each other's changes. The new content is
+/// written to a temporary file next to the original, synced to disk and renamed over it, so
+/// a crash leaves either the old or the new version and never a truncated file. Readers don't
+/// lock, as the rename makes them see one version or the other.
+///
+/// With `advisory_lock`, updates also take an exclusive lock on a `<file>.lock` file, which
+/// serializes writers in other processes that use it too. With `backup`, the previous version
+/// is kept as `<file>.bak` and can be restored with `rollback`.
+#[derive(Clone, Debug)]
+pub struct JsonFile {
+    path: PathBuf,
+    advisory_lock: bool,
+    backup: bool,
+}
+
+impl JsonFile {
+    pub fn new(path: impl AsRef<Path>) -> JsonFile {
+        JsonFile {
+            path: path.as_ref().to_path_buf(),
+            advisory_lock: false,
+            backup: false,
+        }
+    }
+
+    /// Also locks `<file>.lock` during updates, to coordinate with other processes.
+    pub fn advisory_lock(mut self, enabled: bool) -> JsonFile {
+        self.advisory_lock = enabled;
+        self
+    }
+
+    /// Keeps the version replaced by each write as `<file>.bak`.
+    pub fn backup(mut self, enabled: bool) -> JsonFile {
+        self.backup = enabled;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Reads the document, or `Value::Null` if the file does not exist.
+    pub fn read(&self) -> io::Result<Value> {
+        match fs::read_to_string(&self.path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Value::Null),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the document, lets `f` modify it and writes it back, all under the file's lock.
+    ///
+    /// A missing file is passed to `f` as `Value::Null`. If `f` returns `Err`, nothing is written.
+    pub fn update<T, E>(&self, f: impl FnOnce(&mut Value) -> Result<T, E>) -> io::Result<Result<T, E>> {
+        self.locked(|| {
+            let mut document = self.read()?;
+            let result = f(&mut document);
+            if result.is_ok() {
+                self.replace(&document)?;
+            }
+            Ok(result)
+        })
+    }
+
+    /// Replaces the document.
+    pub fn write(&self, document: &Value) -> io::Result<()> {
+        self.locked(|| self.replace(document))
+    }
+
+    /// Restores the version saved by the last write made with `backup` enabled.
+    pub fn rollback(&self) -> io::Result<()> {
+        self.locked(|| {
+            fs::rename(with_suffix(&self.path, ".bak"), &self.path)?;
+            sync_parent(&self.path)
+        })
+    }
+
+    fn locked<T>(&self, f: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+        let lock = process_lock(&self.path);
+        let _guard = lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        let lock_file = if self.advisory_lock {
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .write(true)
+                .open(with_suffix(&self.path, ".lock"))?;
+            file.lock()?;
+            Some(file)
+        } else {
+            None
+        };
+
+        let result = f();
+        if let Some(file) = lock_file {
+            file.unlock()?;
+        }
+        result
+    }
+
+    fn replace(&self, document: &Value) -> io::Result<()> {
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let temp = with_suffix(
+            &self.path,
+            &format!(".{}.{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)),
+        );
+
+        let written = (|| {
+            let mut file = File::create(&temp)?;
+            serde_json::to_writer_pretty(&mut file, document)?;
+            file.write_all(b"\n")?;
+            file.sync_all()
+        })();
+        if let Err(e) = written {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+
+        if self.backup && self.path.exists() {
+            // A hard link keeps the old version without copying it; the rename below then
+            // only replaces the original name
+            let backup = with_suffix(&self.path, ".bak");
+            let _ = fs::remove_file(&backup);
+            if fs::hard_link(&self.path, &backup).is_err() {
+                fs::copy(&self.path, &backup)?;
+            }
+        }
+
+        if let Err(e) = fs::rename(&temp, &self.path) {
+            let _ = fs::remove_file(&temp);
+            return Err(e);
+        }
+        sync_parent(&self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("parfait-file-store-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn writes_replace_the_file_without_leftovers() {
+        let dir = temp_dir("replace");
+        let file = JsonFile::new(dir.join("data.json"));
+        assert_eq!(file.read().unwrap(), Value::Null);
+        file.write(&json!({ "a": 1 })).unwrap();
+        file.write(&json!({ "a": 2 })).unwrap();
+        assert_eq!(file.read().unwrap(), json!({ "a": 2 }));
+        assert_eq!(entries(&dir), vec!["data.json"]);
+
+        // The rename fails when a directory is in the way; the temporary file is cleaned up
+        let blocked = JsonFile::new(dir.join("blocked"));
+        fs::create_dir_all(dir.join("blocked").join("child")).unwrap();
+        assert!(blocked.write(&json!([])).is_err());
+        assert_eq!(entries(&dir), vec!["blocked", "data.json"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_updates_write_nothing_and_backups_roll_back() {
+        let dir = temp_dir("backup");
+        let file = JsonFile::new(dir.join("data.json")).backup(true);
+        file.write(&json!({ "version": 1 })).unwrap();
+        file.write(&json!({ "version": 2 })).unwrap();
+
+        let failed = file.update(|document| {
+            document["version"] = json!(3);
+            Err::<(), _>("invalid")
+        });
+        assert_eq!(failed.unwrap(), Err("invalid"));
+        assert_eq!(file.read().unwrap(), json!({ "version": 2 }));
+
+        file.rollback().unwrap();
+        assert_eq!(file.read().unwrap(), json!({ "version": 1 }));
+        assert!(file.rollback().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = temp_dir("concurrent");
+        let path = dir.join("counter.json");
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                // Separate handles, some also taking the advisory lock, share the file's lock
+                let file = JsonFile::new(&path).advisory_lock(i % 2 == 0);
+                std::thread::spawn(move || {
+                    for _ in 0..25 {
+                        file.update(|document| {
+                            let count = document.as_u64().unwrap_or(0);
+                            *document = json!(count + 1);
+                            Ok::<(), ()>(())
+                        })
+                        .unwrap()
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(JsonFile::new(&path).read().unwrap(), json!(200));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{escape_html, with_session};
+
+const FLASH_KEY: &str = "_flash";
+
+/// A one-time message shown on the next page the client loads.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Flash {
+    pub level: String,
+    pub message: String,
+}
+
+/// Queues a flash message in the current session, e.g. `flash("success", "Saved.")` before
+/// returning a `redirect`. Does nothing unless `SessionMiddleware` is installed.
+pub fn flash(level: &str, message: &str) {
+    with_session(|session| {
+        let mut flashes: Vec<Flash> = session.get(FLASH_KEY).unwrap_or_default();
+        flashes.push(Flash {
+            level: level.to_owned(),
+            message: message.to_owned(),
+        });
+        session.insert(FLASH_KEY, flashes);
+    });
+}
+
+/// Removes and returns the queued flash messages.
+pub fn take_flashes() -> Vec<Flash> {
+    with_session(|session| {
+        let flashes: Option<Vec<Flash>> = session.get(FLASH_KEY);
+        if flashes.is_some() {
+            session.remove(FLASH_KEY);
+        }
+        flashes.unwrap_or_default()
+    })
+    .unwrap_or_default()
+}
+
+/// Replaces `{{ flash }}` in a template with the queued messages, consuming them.
+///
+/// Each message becomes `<div class="flash flash-{level}">{message}</div>`. Templates without
+/// the placeholder are returned unchanged and leave the messages queued.
+pub fn render_flash(template: &str) -> String {
+    if !template.contains("{{ flash }}") {
+        return template.to_owned();
+    }
+    let html: String = take_flashes()
+        .iter()
+        .map(|f| {
+            format!(
+                "<div class=\"flash flash-{}\">{}</div>",
+                escape_html(&f.level),
+                escape_html(&f.message)
+            )
+        })
+        .collect();
+    template.replace("{{ flash }}", &html)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{context, MemoryStore, Middleware, SessionMiddleware};
+
+    /// Runs one request through `sessions`, returning the session id to send next time.
+    fn request(sessions: &SessionMiddleware, id: Option<&str>, handler: impl FnOnce()) -> Option<String> {
+        let request = match id {
+            Some(id) => format!("GET / HTTP/1.1\r\nCookie: parfait_session={}\r\n\r\n", id),
+            None => "GET / HTTP/1.1\r\n\r\n".to_owned(),
+        };
+        let response = context::scope(None, || {
+            sessions.before(&request);
+            handler();
+            sessions.after("HTTP/1.1 200 OK\r\n\r\n")
+        });
+        let issued = response.and_then(|response| {
+            let start = response.find("parfait_session=")? + "parfait_session=".len();
+            response[start..].split(';').next().map(str::to_owned)
+        });
+        issued.or_else(|| id.map(str::to_owned))
+    }
+
+    #[test]
+    fn flashes_are_shown_exactly_once() {
+        let sessions = SessionMiddleware::new(Arc::new(MemoryStore::new()));
+        let id = request(&sessions, None, || {
+            flash("success", "Saved.");
+            flash("error", "<b>Careful</b>");
+        });
+
+        let id = request(&sessions, id.as_deref(), || {
+            let page = render_flash("<main>{{ flash }}</main>");
+            assert_eq!(
+                page,
+                "<main><div class=\"flash flash-success\">Saved.</div>\
+                 <div class=\"flash flash-error\">&lt;b&gt;Careful&lt;/b&gt;</div></main>"
+            );
+        });
+        request(&sessions, id.as_deref(), || {
+            assert!(take_flashes().is_empty());
+            assert_eq!(render_flash("{{ flash }}"), "");
+        });
+    }
+
+    #[test]
+    fn templates_without_the_placeholder_keep_flashes_queued() {
+        let sessions = SessionMiddleware::new(Arc::new(MemoryStore::new()));
+        let id = request(&sessions, None, || flash("info", "Hello"));
+        let id = request(&sessions, id.as_deref(), || {
+            assert_eq!(render_flash("<p>no flashes here</p>"), "<p>no flashes here</p>");
+        });
+        request(&sessions, id.as_deref(), || {
+            let flashes = take_flashes();
+            assert_eq!(flashes.len(), 1);
+            assert_eq!(flashes[0].message, "Hello");
+        });
+    }
+
+    #[test]
+    fn flashes_need_a_session() {
+        flash("info", "lost");
+        assert!(take_flashes().is_empty());
+    }
+}
@@ -0,0 +1,165 @@
+use std::sync::Arc;
+
+use crate::{request_target, Authenticator, Identity, Middleware, Problem};
+
+struct Rule {
+    method: String,
+    path: String,
+    roles: Vec<String>,
+    scopes: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, method: &str, path: &str) -> bool {
+        (self.method == "*" || self.method == method) && within(path, &self.path)
+    }
+
+    fn allows(&self, identity: &Identity) -> bool {
+        self.roles.iter().all(|role| identity.has_role(role))
+            && self.scopes.iter().all(|scope| identity.has_scope(scope))
+    }
+}
+
+/// Whether `path` is `prefix` or below it, so `/admin` covers `/admin/users` but not `/administrator`.
+fn within(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.is_empty() || prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Per-route authorization middleware.
+///
+/// Routes are declared with a method and a path, which also guards every path below it, followed
+/// by the roles or scopes they need:
+///
+/// ```text
+/// Guard::new(auth)
+///     .route("POST", "/items").require_scope("items:write")
+///     .route("*", "/admin").require_role("admin")
+/// ```
+///
+/// Requests to unguarded routes pass through untouched. A guarded request without a valid
+/// identity gets `401`, and one whose identity lacks a required role or scope gets `403`.
+pub struct Guard {
+    authenticator: Arc<dyn Authenticator>,
+    rules: Vec<Rule>,
+}
+
+impl Guard {
+    pub fn new(authenticator: Arc<dyn Authenticator>) -> Guard {
+        Guard {
+            authenticator,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Declares a guarded route. `method` may be `"*"` to match any method.
+    pub fn route(mut self, method: &str, path: &str) -> Guard {
+        self.push_rule(method, path);
+        self
+    }
+
+    /// Requires a role on the most recently declared route (or on every route if none is declared yet).
+    pub fn require_role(mut self, role: &str) -> Guard {
+        self.last_rule().roles.push(role.to_owned());
+        self
+    }
+
+    /// Requires a scope on the most recently declared route (or on every route if none is declared yet).
+    pub fn require_scope(mut self, scope: &str) -> Guard {
+        self.last_rule().scopes.push(scope.to_owned());
+        self
+    }
+
+    fn last_rule(&mut self) -> &mut Rule {
+        if self.rules.is_empty() {
+            self.push_rule("*", "");
+        }
+        self.rules.last_mut().unwrap()
+    }
+
+    fn push_rule(&mut self, method: &str, path: &str) {
+        self.rules.push(Rule {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            roles: Vec::new(),
+            scopes: Vec::new(),
+        });
+    }
+}
+
+impl Middleware for Guard {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, path, _) = request_target(request)?;
+        let rules: Vec<&Rule> = self.rules.iter().filter(|rule| rule.matches(method, path)).collect();
+        if rules.is_empty() {
+            return None;
+        }
+
+        match self.authenticator.authenticate(request) {
+            None => Some(Problem::new(401).response()),
+            Some(identity) if rules.iter().all(|rule| rule.allows(&identity)) => None,
+            Some(_) => Some(Problem::new(403).detail("Missing a required role or scope.").response()),
+        }
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::ApiKeyMiddleware;
+
+    fn guard() -> Guard {
+        let keys = HashMap::from([
+            ("admin-key".to_owned(), Identity::new("ann").with_claim("roles", json!(["admin"]))),
+            (
+                "writer-key".to_owned(),
+                Identity::new("bob").with_claim("scope", json!("items:read items:write")),
+            ),
+        ]);
+        Guard::new(Arc::new(ApiKeyMiddleware::new(keys)))
+            .route("POST", "/items").require_scope("items:write")
+            .route("*", "/admin").require_role("admin")
+    }
+
+    fn status(guard: &Guard, method: &str, path: &str, key: Option<&str>) -> Option<u16> {
+        let key = key.map_or(String::new(), |key| format!("X-API-Key: {}\r\n", key));
+        let response = guard.before(&format!("{} {} HTTP/1.1\r\n{}\r\n", method, path, key))?;
+        response[9..12].parse().ok()
+    }
+
+    #[test]
+    fn missing_identities_get_401_and_missing_grants_403() {
+        let guard = guard();
+        assert_eq!(status(&guard, "POST", "/items", None), Some(401));
+        assert_eq!(status(&guard, "POST", "/items", Some("wrong")), Some(401));
+        assert_eq!(status(&guard, "POST", "/items", Some("admin-key")), Some(403));
+        assert_eq!(status(&guard, "POST", "/items", Some("writer-key")), None);
+        assert_eq!(status(&guard, "DELETE", "/admin/users/1", Some("writer-key")), Some(403));
+        assert_eq!(status(&guard, "DELETE", "/admin/users/1", Some("admin-key")), None);
+        assert_eq!(status(&guard, "GET", "/items", None), None);
+    }
+
+    #[test]
+    fn routes_match_whole_path_segments() {
+        let guard = guard();
+        assert_eq!(status(&guard, "GET", "/admin", None), Some(401));
+        assert_eq!(status(&guard, "GET", "/admin/", None), Some(401));
+        assert_eq!(status(&guard, "GET", "/admin/users", None), Some(401));
+        assert_eq!(status(&guard, "GET", "/administrator", None), None);
+        assert_eq!(status(&guard, "GET", "/adminx/users", None), None);
+
+        assert!(within("/anything", ""));
+        assert!(within("/api/v1", "/api/"));
+        assert!(!within("/api", "/api/"));
+    }
+}
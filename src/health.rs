@@ -0,0 +1,205 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::{request_target, Middleware};
+
+type CheckFuture = Pin<Box<dyn Future<Output = Result<(), String>> + Send>>;
+type CheckFn = Arc<dyn Fn() -> CheckFuture + Send + Sync>;
+
+struct Check {
+    name: String,
+    liveness: bool,
+    timeout: Duration,
+    run: CheckFn,
+}
+
+#[derive(Clone)]
+struct CheckResult {
+    error: Option<String>,
+    duration: Duration,
+}
+
+/// Built-in `/healthz` (liveness) and `/readyz` (readiness) endpoints.
+///
+/// Checks are async functions returning `Ok(())` when healthy. Because middleware runs
+/// synchronously, they are not run per request: [`HealthMiddleware::spawn_checks`] runs them all
+/// in the background on an interval, each under its own timeout, and the endpoints report the
+/// latest results as JSON with `200 OK` or `503 Service Unavailable`.
+///
+/// Liveness only considers liveness checks, and passes until they have run once. Readiness
+/// considers every check, fails until they have all run, and fails as soon as
+/// [`HealthMiddleware::begin_shutdown`] is called so traffic drains before the process exits.
+pub struct HealthMiddleware {
+    liveness_path: String,
+    readiness_path: String,
+    checks: Vec<Check>,
+    results: RwLock<BTreeMap<String, CheckResult>>,
+    shutting_down: AtomicBool,
+}
+
+impl Default for HealthMiddleware {
+    fn default() -> Self {
+        HealthMiddleware::new()
+    }
+}
+
+impl HealthMiddleware {
+    pub fn new() -> HealthMiddleware {
+        HealthMiddleware {
+            liveness_path: "/healthz".to_owned(),
+            readiness_path: "/readyz".to_owned(),
+            checks: Vec::new(),
+            results: RwLock::new(BTreeMap::new()),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Serves the endpoints on different paths.
+    pub fn paths(mut self, liveness: &str, readiness: &str) -> HealthMiddleware {
+        self.liveness_path = liveness.to_owned();
+        self.readiness_path = readiness.to_owned();
+        self
+    }
+
+    /// Adds a check that decides whether the process is alive (and therefore also ready).
+    pub fn liveness_check<F, Fut>(self, name: &str, timeout: Duration, check: F) -> HealthMiddleware
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.check(name, true, timeout, check)
+    }
+
+    /// Adds a check that decides whether the process can take traffic, e.g. a database ping.
+    pub fn readiness_check<F, Fut>(self, name: &str, timeout: Duration, check: F) -> HealthMiddleware
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.check(name, false, timeout, check)
+    }
+
+    fn check<F, Fut>(mut self, name: &str, liveness: bool, timeout: Duration, check: F) -> HealthMiddleware
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.checks.push(Check {
+            name: name.to_owned(),
+            liveness,
+            timeout,
+            run: Arc::new(move || Box::pin(check()) as CheckFuture),
+        });
+        self
+    }
+
+    /// Spawns a task that runs every check every `every`.
+    pub fn spawn_checks(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let health = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                health.run_checks().await;
+            }
+        })
+    }
+
+    /// Runs every check once, concurrently, and stores the results.
+    pub async fn run_checks(&self) {
+        let running: Vec<_> = self
+            .checks
+            .iter()
+            .map(|check| {
+                let (run, timeout) = (check.run.clone(), check.timeout);
+                tokio::spawn(async move {
+                    let started = Instant::now();
+                    let error = match tokio::time::timeout(timeout, run()).await {
+                        Ok(Ok(())) => None,
+                        Ok(Err(e)) => Some(e),
+                        Err(_) => Some(format!("timed out after {:?}", timeout)),
+                    };
+                    CheckResult {
+                        error,
+                        duration: started.elapsed(),
+                    }
+                })
+            })
+            .collect();
+
+        for (check, task) in self.checks.iter().zip(running) {
+            let result = task.await.unwrap_or_else(|_| CheckResult {
+                error: Some("check panicked".to_owned()),
+                duration: Duration::ZERO,
+            });
+            self.results.write().unwrap().insert(check.name.clone(), result);
+        }
+    }
+
+    /// Makes readiness fail from now on. Call this when a graceful shutdown starts.
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    fn report(&self, readiness: bool) -> String {
+        let results = self.results.read().unwrap();
+        let mut healthy = true;
+        let mut checks = serde_json::Map::new();
+
+        for check in self.checks.iter().filter(|check| readiness || check.liveness) {
+            let entry = match results.get(&check.name) {
+                Some(CheckResult { error: None, duration }) => {
+                    json!({ "status": "ok", "duration_ms": duration.as_secs_f64() * 1000.0 })
+                }
+                Some(CheckResult { error: Some(error), duration }) => {
+                    healthy = false;
+                    json!({ "status": "fail", "error": error, "duration_ms": duration.as_secs_f64() * 1000.0 })
+                }
+                None => {
+                    healthy &= !readiness;
+                    json!({ "status": "pending" })
+                }
+            };
+            checks.insert(check.name.clone(), entry);
+        }
+
+        let shutting_down = readiness && self.shutting_down.load(Ordering::SeqCst);
+        healthy &= !shutting_down;
+
+        let mut body = json!({ "status": if healthy { "ok" } else { "fail" }, "checks": checks });
+        if shutting_down {
+            body["shutting_down"] = json!(true);
+        }
+        let status = if healthy { "200 OK" } else { "503 Service Unavailable" };
+        format!(
+            "HTTP/1.1 {}\r\nContent-Type: application/json\r\nCache-Control: no-store\r\n\r\n{}",
+            status, body
+        )
+    }
+}
+
+impl Middleware for HealthMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, path, _) = request_target(request)?;
+        if method != "GET" && method != "HEAD" {
+            return None;
+        }
+        if path == self.liveness_path {
+            Some(self.report(false))
+        } else if path == self.readiness_path {
+            Some(self.report(true))
+        } else {
+            None
+        }
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+}
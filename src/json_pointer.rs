@@ -0,0 +1,144 @@
+use serde_json::Value;
+
+/// Why a JSON Pointer could not be applied.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum PointerError {
+    /// The pointer is malformed or addresses something that cannot be changed, e.g. the root.
+    Invalid(String),
+    /// The pointer is well-formed but does not resolve in the document.
+    NotFound(String),
+}
+
+/// Splits an RFC 6901 JSON Pointer into its unescaped reference tokens.
+pub(crate) fn parse(pointer: &str) -> Result<Vec<String>, PointerError> {
+    if pointer.is_empty() {
+        return Ok(Vec::new());
+    }
+    let rest = pointer
+        .strip_prefix('/')
+        .ok_or_else(|| PointerError::Invalid(format!("'{}' is not a JSON Pointer", pointer)))?;
+    let bad_escape = rest
+        .match_indices('~')
+        .any(|(i, _)| !matches!(rest.as_bytes().get(i + 1), Some(b'0') | Some(b'1')));
+    if bad_escape {
+        return Err(PointerError::Invalid(format!("'{}' has an invalid '~' escape", pointer)));
+    }
+    Ok(rest.split('/').map(|token| token.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+/// Parses an array index token: digits only, no leading zeros.
+pub(crate) fn index(token: &str) -> Option<usize> {
+    let valid = !token.is_empty() && token.bytes().all(|b| b.is_ascii_digit()) && (token == "0" || !token.starts_with('0'));
+    if valid {
+        token.parse().ok()
+    } else {
+        None
+    }
+}
+
+/// Finds the array element whose `"id"` equals `id`, comparing numbers by their text.
+pub(crate) fn position_by_id(items: &[Value], id: &str) -> Option<usize> {
+    items.iter().position(|item| match item.get("id") {
+        Some(Value::String(value)) => value == id,
+        Some(Value::Number(value)) => value.to_string() == id,
+        _ => false,
+    })
+}
+
+/// Resolves `tokens` to a mutable reference, strictly as RFC 6901 describes: array elements are
+/// addressed by index only.
+pub(crate) fn resolve_mut<'v>(document: &'v mut Value, tokens: &[String]) -> Result<&'v mut Value, PointerError> {
+    let mut current = document;
+    for (depth, token) in tokens.iter().enumerate() {
+        let at = || format!("/{}", tokens[..=depth].join("/"));
+        current = match current {
+            Value::Object(map) => map.get_mut(token).ok_or_else(|| PointerError::NotFound(at()))?,
+            Value::Array(items) => {
+                let position = index(token)
+                    .filter(|&i| i < items.len())
+                    .ok_or_else(|| PointerError::NotFound(at()))?;
+                &mut items[position]
+            }
+            _ => return Err(PointerError::Invalid(format!("{} is not an object or array", at()))),
+        };
+    }
+    Ok(current)
+}
+
+/// Removes and returns the value `pointer` addresses.
+pub(crate) fn remove(document: &mut Value, pointer: &str) -> Result<Value, PointerError> {
+    let tokens = parse(pointer)?;
+    let (last, parent) = tokens
+        .split_last()
+        .ok_or_else(|| PointerError::Invalid("the whole document cannot be removed".to_owned()))?;
+    match resolve_mut(document, parent)? {
+        Value::Object(map) => map.remove(last).ok_or_else(|| PointerError::NotFound(pointer.to_owned())),
+        Value::Array(items) => {
+            let position = index(last)
+                .filter(|&i| i < items.len())
+                .ok_or_else(|| PointerError::NotFound(pointer.to_owned()))?;
+            Ok(items.remove(position))
+        }
+        _ => Err(PointerError::Invalid(format!("the parent of {} is not an object or array", pointer))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn tokens(pointer: &str) -> Vec<String> {
+        parse(pointer).unwrap()
+    }
+
+    #[test]
+    fn parses_and_unescapes_tokens() {
+        assert_eq!(tokens(""), Vec::<String>::new());
+        assert_eq!(tokens("/"), vec![""]);
+        assert_eq!(tokens("/a~1b/m~0n/~01"), vec!["a/b", "m~n", "~1"]);
+    }
+
+    #[test]
+    fn rejects_malformed_pointers() {
+        assert!(matches!(parse("a/b"), Err(PointerError::Invalid(_))));
+        assert!(matches!(parse("/a~2"), Err(PointerError::Invalid(_))));
+        assert!(matches!(parse("/a~"), Err(PointerError::Invalid(_))));
+    }
+
+    #[test]
+    fn parses_array_indexes() {
+        assert_eq!(index("0"), Some(0));
+        assert_eq!(index("12"), Some(12));
+        assert_eq!(index("01"), None);
+        assert_eq!(index("-"), None);
+        assert_eq!(index("+1"), None);
+        assert_eq!(index(""), None);
+    }
+
+    #[test]
+    fn resolves_objects_and_arrays() {
+        let mut document = json!({ "a/b": [{ "c": 1 }, { "c": 2 }] });
+        assert_eq!(resolve_mut(&mut document, &tokens("/a~1b/1/c")), Ok(&mut json!(2)));
+        assert_eq!(resolve_mut(&mut document, &tokens("/a~1b/2")), Err(PointerError::NotFound("/a/b/2".to_owned())));
+        assert!(matches!(resolve_mut(&mut document, &tokens("/a~1b/0/c/d")), Err(PointerError::Invalid(_))));
+    }
+
+    #[test]
+    fn array_tokens_never_match_ids() {
+        let mut document = json!({ "users": [{ "id": 5 }, { "id": "x" }] });
+        assert!(matches!(resolve_mut(&mut document, &tokens("/users/x")), Err(PointerError::NotFound(_))));
+        assert!(matches!(remove(&mut document, "/users/5"), Err(PointerError::NotFound(_))));
+        assert_eq!(position_by_id(document["users"].as_array().unwrap(), "5"), Some(0));
+    }
+
+    #[test]
+    fn removes_values() {
+        let mut document = json!({ "a": { "b": 1, "c": 2 }, "list": [1, 2, 3] });
+        assert_eq!(remove(&mut document, "/a/b"), Ok(json!(1)));
+        assert_eq!(remove(&mut document, "/list/0"), Ok(json!(1)));
+        assert_eq!(document, json!({ "a": { "c": 2 }, "list": [2, 3] }));
+        assert!(matches!(remove(&mut document, ""), Err(PointerError::Invalid(_))));
+    }
+}
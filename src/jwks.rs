@@ -0,0 +1,454 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{DecodingKey, Validation};
+
+/// Where a JSON Web Key Set is loaded from.
+#[derive(Clone, Debug)]
+pub enum JwksSource {
+    File(String),
+    Url(String),
+}
+
+struct JwksState {
+    keys: HashMap<String, DecodingKey>,
+    unnamed: Vec<DecodingKey>,
+    fetched_at: Option<Instant>,
+    attempted_at: Option<Instant>,
+}
+
+/// A cached JSON Web Key Set used to verify signed bearer tokens.
+///
+/// Keys are reloaded once `max_age` has passed, or early when a token names a `kid` that is not
+/// in the cache. Reloads are attempted at most once per `min_refresh_interval`, so unknown or
+/// forged `kid`s cannot hammer the key source.
+///
+/// The first `verify` loads the keys before checking the token. After that, inside a Tokio
+/// runtime, `verify` never waits for a key source: a reload it triggers runs on the blocking pool
+/// and the token is checked against the keys already cached. Call `refresh` at startup to keep
+/// the first load off the request path.
+pub struct Jwks {
+    source: Arc<JwksSource>,
+    state: Arc<RwLock<JwksState>>,
+    refreshing: Arc<AtomicBool>,
+    max_age: Duration,
+    min_refresh_interval: Duration,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Jwks {
+    pub fn new(source: JwksSource) -> Jwks {
+        Jwks {
+            source: Arc::new(source),
+            state: Arc::new(RwLock::new(JwksState {
+                keys: HashMap::new(),
+                unnamed: Vec::new(),
+                fetched_at: None,
+                attempted_at: None,
+            })),
+            refreshing: Arc::new(AtomicBool::new(false)),
+            max_age: Duration::from_secs(3600),
+            min_refresh_interval: Duration::from_secs(30),
+            issuer: None,
+            audience: None,
+        }
+    }
+
+    /// Loads the key set from a local JSON file.
+    pub fn from_file(path: &str) -> Jwks {
+        Jwks::new(JwksSource::File(path.to_owned()))
+    }
+
+    /// Loads the key set from an `http://` URL.
+    pub fn from_url(url: &str) -> Jwks {
+        Jwks::new(JwksSource::Url(url.to_owned()))
+    }
+
+    /// How long fetched keys are trusted before they are reloaded.
+    pub fn max_age(mut self, max_age: Duration) -> Jwks {
+        self.max_age = max_age;
+        self
+    }
+
+    /// Minimum time between two automatic reload attempts.
+    pub fn min_refresh_interval(mut self, interval: Duration) -> Jwks {
+        self.min_refresh_interval = interval;
+        self
+    }
+
+    /// Requires tokens to carry this `iss` claim.
+    pub fn issuer(mut self, issuer: &str) -> Jwks {
+        self.issuer = Some(issuer.to_owned());
+        self
+    }
+
+    /// Requires tokens to carry this `aud` claim.
+    pub fn audience(mut self, audience: &str) -> Jwks {
+        self.audience = Some(audience.to_owned());
+        self
+    }
+
+    /// Reloads the key set from its source, replacing the cached keys. Blocks while the source
+    /// is read.
+    pub fn refresh(&self) -> io::Result<()> {
+        reload(&self.source, &self.state)
+    }
+
+    /// Spawns a task that reloads the key set every `every`, independent of incoming tokens.
+    pub fn spawn_refresh(self: &Arc<Self>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let jwks = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                let jwks = jwks.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || jwks.refresh()).await {
+                    error!("Failed to refresh JWKS: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Verifies a compact JWT against the key set and returns its claims.
+    pub fn verify(&self, token: &str) -> Option<serde_json::Value> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+
+        self.refresh_if(|state| {
+            let expired = match state.fetched_at {
+                None => true,
+                Some(at) => at.elapsed() >= self.max_age,
+            };
+            let unknown_kid = match &header.kid {
+                Some(kid) => !state.keys.contains_key(kid),
+                None => false,
+            };
+            expired || unknown_kid
+        });
+
+        let mut validation = Validation::new(header.alg);
+        match &self.audience {
+            Some(aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(iss) = &self.issuer {
+            validation.set_issuer(&[iss]);
+        }
+
+        let state = self.state.read().unwrap();
+        let candidates: Vec<&DecodingKey> = match &header.kid {
+            Some(kid) => state.keys.get(kid).into_iter().collect(),
+            None => state.keys.values().chain(state.unnamed.iter()).collect(),
+        };
+        candidates.into_iter().find_map(|key| {
+            jsonwebtoken::decode::<serde_json::Value>(token, key, &validation)
+                .ok()
+                .map(|data| data.claims)
+        })
+    }
+
+    fn refresh_if(&self, stale: impl Fn(&JwksState) -> bool) {
+        let cold = {
+            let state = self.state.read().unwrap();
+            let throttled = match state.attempted_at {
+                Some(at) => at.elapsed() < self.min_refresh_interval,
+                None => false,
+            };
+            if throttled || !stale(&state) {
+                return;
+            }
+            state.fetched_at.is_none()
+        };
+        // One reload at a time; concurrent requests keep using the cached keys
+        if self.refreshing.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let source = self.source.clone();
+        let state = self.state.clone();
+        let refreshing = self.refreshing.clone();
+        let task = move || {
+            if let Err(e) = reload(&source, &state) {
+                error!("Failed to refresh JWKS: {}", e);
+                // Back off so a broken source is not hit on every request
+                state.write().unwrap().attempted_at = Some(Instant::now());
+            }
+            refreshing.store(false, Ordering::Release);
+        };
+        match tokio::runtime::Handle::try_current() {
+            // With no keys cached yet there is nothing to check against, so the load is waited for
+            Ok(runtime) if !cold => drop(runtime.spawn_blocking(task)),
+            _ => task(),
+        }
+    }
+}
+
+/// Reads the key set from `source` and replaces the keys cached in `state`.
+fn reload(source: &JwksSource, state: &RwLock<JwksState>) -> io::Result<()> {
+    let document = match source {
+        JwksSource::File(path) => std::fs::read_to_string(path)?,
+        JwksSource::Url(url) => fetch(url)?,
+    };
+    let set: JwkSet = serde_json::from_str(&document)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let mut keys = HashMap::new();
+    let mut unnamed = Vec::new();
+    for jwk in &set.keys {
+        // Skip keys this library cannot use instead of rejecting the whole set
+        let key = match DecodingKey::from_jwk(jwk) {
+            Ok(key) => key,
+            Err(_) => continue,
+        };
+        match &jwk.common.key_id {
+            Some(kid) => {
+                keys.insert(kid.clone(), key);
+            }
+            None => unnamed.push(key),
+        }
+    }
+
+    let mut state = state.write().unwrap();
+    state.keys = keys;
+    state.unnamed = unnamed;
+    state.fetched_at = Some(Instant::now());
+    state.attempted_at = state.fetched_at;
+    Ok(())
+}
+
+/// Minimal blocking HTTP/1.1 GET used to download a key set.
+fn fetch(url: &str) -> io::Result<String> {
+    let rest = url.strip_prefix("http://").ok_or_else(|| {
+        io::Error::new(io::ErrorKind::Unsupported, "only http:// JWKS URLs are supported")
+    })?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    let address = if authority.contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{}:80", authority)
+    };
+
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, format!("cannot resolve {}", authority));
+    let mut stream = None;
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, Duration::from_secs(5)) {
+            Ok(connected) => {
+                stream = Some(connected);
+                break;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+    let mut stream = stream.ok_or(last_error)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(5)))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: application/json\r\nConnection: close\r\n\r\n",
+        path, authority
+    )?;
+
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+
+    let status = head.lines().next().unwrap_or("");
+    if status.split(' ').nth(1) != Some("200") {
+        return Err(io::Error::other(format!("JWKS request failed: {}", status)));
+    }
+
+    let chunked = head.lines().any(|line| {
+        let line = line.to_ascii_lowercase();
+        line.starts_with("transfer-encoding:") && line.contains("chunked")
+    });
+    if chunked {
+        Ok(decode_chunked(body))
+    } else {
+        Ok(body.to_owned())
+    }
+}
+
+fn decode_chunked(mut body: &str) -> String {
+    let mut decoded = String::new();
+    while let Some((size, rest)) = body.split_once("\r\n") {
+        let size = usize::from_str_radix(size.split(';').next().unwrap_or("").trim(), 16).unwrap_or(0);
+        let (chunk, rest) = match (rest.get(..size), rest.get(size..)) {
+            (Some(chunk), Some(rest)) if size > 0 => (chunk, rest),
+            _ => break,
+        };
+        decoded.push_str(chunk);
+        body = rest.trim_start_matches("\r\n");
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    use base64::Engine;
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    fn key_set(kids: &[&str]) -> String {
+        let keys: Vec<_> = kids
+            .iter()
+            .map(|kid| {
+                serde_json::json!({
+                    "kty": "oct",
+                    "kid": kid,
+                    "alg": "HS256",
+                    "k": base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(format!("secret-{}", kid)),
+                })
+            })
+            .collect();
+        serde_json::json!({ "keys": keys }).to_string()
+    }
+
+    fn token(kid: &str, claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some(kid.to_owned());
+        let key = EncodingKey::from_secret(format!("secret-{}", kid).as_bytes());
+        jsonwebtoken::encode(&header, &claims, &key).unwrap()
+    }
+
+    fn expires_in(seconds: i64) -> i64 {
+        jsonwebtoken::get_current_timestamp() as i64 + seconds
+    }
+
+    /// A key server answering every request with the current document, chunked, and counting
+    /// the requests.
+    fn stub_server(document: Arc<Mutex<String>>, requests: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/.well-known/jwks.json", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buffer) {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => request.extend_from_slice(&buffer[..n]),
+                    }
+                }
+                requests.fetch_add(1, Ordering::SeqCst);
+                let body = document.lock().unwrap().clone();
+                let (first, second) = body.split_at(body.len() / 2);
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n{:x}\r\n{}\r\n{:x}\r\n{}\r\n0\r\n\r\n",
+                    first.len(),
+                    first,
+                    second.len(),
+                    second
+                );
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn loads_keys_from_a_file() {
+        let path = std::env::temp_dir().join(format!("parfait-jwks-{}.json", std::process::id()));
+        std::fs::write(&path, key_set(&["a"])).unwrap();
+        let jwks = Jwks::from_file(path.to_str().unwrap());
+        let claims = jwks.verify(&token("a", serde_json::json!({ "sub": "ada", "exp": expires_in(60) })));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(claims.unwrap()["sub"], "ada");
+    }
+
+    #[test]
+    fn loads_keys_from_a_url() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = stub_server(Arc::new(Mutex::new(key_set(&["a"]))), requests.clone());
+        let jwks = Jwks::from_url(&url);
+        jwks.refresh().unwrap();
+        assert!(jwks.verify(&token("a", serde_json::json!({ "exp": expires_in(60) }))).is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn first_verify_in_a_runtime_waits_for_the_keys() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = stub_server(Arc::new(Mutex::new(key_set(&["a"]))), requests);
+        let jwks = Jwks::from_url(&url);
+        assert!(jwks.verify(&token("a", serde_json::json!({ "exp": expires_in(60) }))).is_some());
+    }
+
+    #[test]
+    fn decodes_chunked_bodies() {
+        let body = "4\r\nWiki\r\n6;ext=1\r\npedia \r\nE\r\nin \r\n\r\nchunks.\r\n0\r\n\r\n";
+        assert_eq!(decode_chunked(body), "Wikipedia in \r\n\r\nchunks.");
+        assert_eq!(decode_chunked("0\r\n\r\n"), "");
+        assert_eq!(decode_chunked("zz\r\nabc"), "");
+    }
+
+    #[test]
+    fn reloads_when_a_token_names_an_unknown_kid() {
+        let document = Arc::new(Mutex::new(key_set(&["old"])));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = stub_server(document.clone(), requests.clone());
+        let jwks = Jwks::from_url(&url).min_refresh_interval(Duration::ZERO);
+        jwks.refresh().unwrap();
+
+        *document.lock().unwrap() = key_set(&["old", "new"]);
+        let claims = jwks.verify(&token("new", serde_json::json!({ "exp": expires_in(60) })));
+        assert!(claims.is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(jwks.verify(&token("old", serde_json::json!({ "exp": expires_in(60) }))).is_some());
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn reloads_are_throttled() {
+        let document = Arc::new(Mutex::new(key_set(&["a"])));
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = stub_server(document.clone(), requests.clone());
+        let jwks = Jwks::from_url(&url).min_refresh_interval(Duration::from_secs(60));
+        jwks.refresh().unwrap();
+
+        *document.lock().unwrap() = key_set(&["a", "b"]);
+        for _ in 0..3 {
+            assert!(jwks.verify(&token("b", serde_json::json!({ "exp": expires_in(60) }))).is_none());
+        }
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rejects_expired_tokens_and_wrong_issuers() {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let url = stub_server(Arc::new(Mutex::new(key_set(&["a"]))), requests);
+        let jwks = Jwks::from_url(&url).issuer("https://id.example.com");
+        jwks.refresh().unwrap();
+
+        let valid = serde_json::json!({ "iss": "https://id.example.com", "exp": expires_in(60) });
+        let expired = serde_json::json!({ "iss": "https://id.example.com", "exp": expires_in(-3600) });
+        let foreign = serde_json::json!({ "iss": "https://evil.example.com", "exp": expires_in(60) });
+        assert!(jwks.verify(&token("a", valid)).is_some());
+        assert!(jwks.verify(&token("a", expired)).is_none());
+        assert!(jwks.verify(&token("a", foreign)).is_none());
+    }
+
+    #[test]
+    fn bearer_tokens_come_from_the_authorization_header_only() {
+        use crate::{AuthMiddleware, Authenticator};
+
+        let auth = AuthMiddleware::new(vec!["t0k3n".to_owned()]);
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nauthorization: bearer t0k3n\r\n\r\n").is_some());
+        assert!(auth.authenticate("GET / HTTP/1.1\r\nAuthorization: Bearer wrong\r\n\r\n").is_none());
+        assert!(auth.authenticate("POST / HTTP/1.1\r\nHost: a\r\n\r\nAuthorization: Bearer t0k3n").is_none());
+    }
+}
@@ -6,21 +6,187 @@ use std::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpStream;
 
+#[macro_use]
+mod trace;
+mod access_log;
+mod auth;
+mod collection;
+mod compression;
+pub mod context;
+mod cookie;
+mod cors;
+mod csrf;
+mod decompression;
+mod file_store;
+mod flash;
+mod guard;
+mod health;
+mod json_pointer;
+mod jwks;
+mod metrics;
+mod patch;
+mod private_cookie;
+mod problem;
+mod rate_limit;
+mod resource;
+mod security_headers;
+mod session;
+mod store;
+mod validation;
+
+pub use access_log::{redact_headers, request_id, AccessLogMiddleware, LogFormat};
+pub use auth::{
+    ApiKeyMiddleware, Authenticator, BasicAuthMiddleware, CredentialVerifier, Identity, PasswordFile,
+    StaticCredentials,
+};
+pub use collection::{json_collection_response, CollectionQuery, Page};
+pub use compression::CompressionMiddleware;
+pub use cookie::{Cookie, CookieJar, SameSite};
+pub use cors::CorsMiddleware;
+pub use csrf::{csrf_field, csrf_token, render_csrf, CsrfMiddleware};
+pub use decompression::set_max_body_size;
+pub use file_store::JsonFile;
+pub use flash::{flash, render_flash, take_flashes, Flash};
+pub use guard::Guard;
+pub use health::HealthMiddleware;
+pub use jwks::{Jwks, JwksSource};
+pub use metrics::MetricsMiddleware;
+pub use private_cookie::{CookieKeys, PrivateJar, SignedJar};
+pub use problem::{json_body, respond, Problem};
+pub use rate_limit::RateLimitMiddleware;
+pub use resource::JsonResource;
+pub use security_headers::{csp_nonce, render_csp_nonce, SecurityHeadersMiddleware};
+pub use session::{
+    with_session, FileStore, MemoryStore, Session, SessionMiddleware, SessionRecord, SessionStore,
+};
+pub use store::JsonStore;
+pub use validation::{JsonSchema, SchemaValidationMiddleware, Violation};
+#[doc(hidden)]
+pub use store::{__store_delete, __store_get, __store_patch, __store_post, __store_put};
+
+/// Remembers the route pattern a route macro matched, for metrics and tracing.
+#[doc(hidden)]
+pub fn __record_route(pattern: &str) {
+    context::insert(metrics::MatchedRoute(pattern.to_owned()));
+    trace::record_route(pattern);
+}
+
+/// Returns the percent-decoded value of a query parameter.
+#[doc(hidden)]
+pub fn __query_param(query: Option<&str>, name: &str) -> Option<String> {
+    query?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if percent_decode(key) == name {
+            Some(percent_decode(value))
+        } else {
+            None
+        }
+    })
+}
+
+/// Removes the value addressed by `key` (a property name or JSON Pointer) from a JSON file.
+#[doc(hidden)]
+pub fn __delete_from_json_file(filename: &str, key: &str, id: Option<&str>) -> String {
+    use json_pointer::PointerError;
+
+    if !std::path::Path::new(filename).exists() {
+        return Problem::new(404).detail("File not found.").response();
+    }
+    let pointer = if key.starts_with('/') {
+        key.to_owned()
+    } else {
+        format!("/{}", key.replace('~', "~0").replace('/', "~1"))
+    };
+
+    // Written back atomically under the file's lock
+    let removed = JsonFile::new(filename).update(|json| match id {
+        Some(id) => {
+            let tokens = json_pointer::parse(&pointer)?;
+            match json_pointer::resolve_mut(json, &tokens)? {
+                serde_json::Value::Array(items) => match json_pointer::position_by_id(items, id) {
+                    Some(position) => Ok(items.remove(position)),
+                    None => Err(PointerError::NotFound(format!("{}/{}", pointer, id))),
+                },
+                _ => Err(PointerError::Invalid(format!("{} is not an array", pointer))),
+            }
+        }
+        None => json_pointer::remove(json, &pointer),
+    });
+    match removed {
+        Ok(Ok(_)) => format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nElement '{}' removed successfully.", key),
+        Ok(Err(PointerError::NotFound(at))) => Problem::new(404).detail(&format!("No element at '{}'.", at)).response(),
+        Ok(Err(PointerError::Invalid(reason))) => {
+            Problem::new(422).detail(&format!("Cannot remove '{}': {}.", key, reason)).response()
+        }
+        // Fallback for non-JSON content, demonstrating intent
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            Problem::new(501).detail("Deletion from non-JSON content not implemented.").response()
+        }
+        Err(e) => {
+            error!("Failed to update {}: {}", filename, e);
+            Problem::new(500).detail("Failed to update file.").response()
+        }
+    }
+}
+
+/// In-memory document of a `patch!` route using `ContentType::String`.
+#[doc(hidden)]
+pub type __JsonDocument = std::sync::Mutex<Option<serde_json::Value>>;
+
+fn patch_response(result: Result<serde_json::Value, patch::PatchError>) -> String {
+    match result {
+        Ok(document) => format!("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n{}", document),
+        Err(e) => Problem::from(e).response(),
+    }
+}
+
+/// Applies a patch request to a JSON file and responds with the updated document.
+#[doc(hidden)]
+pub fn __patch_json_file(filename: &str, content_type: Option<&str>, body: &str) -> String {
+    if !std::path::Path::new(filename).exists() {
+        return Problem::new(404).detail("File not found.").response();
+    }
+    let patched = JsonFile::new(filename).update(|document| {
+        patch::apply(document, content_type, body)?;
+        Ok(document.clone())
+    });
+    match patched {
+        Ok(result) => patch_response(result),
+        Err(e) => {
+            error!("Failed to update {}: {}", filename, e);
+            Problem::new(500).detail("Failed to update file.").response()
+        }
+    }
+}
+
+/// Applies a patch request to an in-memory document and responds with the updated document.
+#[doc(hidden)]
+pub fn __patch_json_value(document: &__JsonDocument, initial: &str, content_type: Option<&str>, body: &str) -> String {
+    let mut document = document.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let current = document.get_or_insert_with(|| serde_json::from_str(initial).unwrap_or(serde_json::Value::Null));
+    patch_response(patch::apply(current, content_type, body).map(|()| current.clone()))
+}
+
 /// Enum to represent the content type for GET macro
+///
+/// The `Store` variant is newer than the others, so code outside the route macros that matches
+/// on `ContentType` exhaustively needs an arm for it.
 pub enum ContentType<'a> {
     File(&'a str),
     String(&'a str),
+    /// A shared in-memory store, addressed by the part of the path after the route's prefix.
+    Store(&'a JsonStore),
 }
 
 /// Defines a GET endpoint.
 ///
 /// # Usage
-/// ```rust
+/// ```text
 /// get!(path, handler_function => filename, headers)
 /// ```
 ///
 /// # Description
-/// Defines a GET endpoint. When a GET request matches the specified `path`, the `handler_function` is invoked to generate a response based on the content of the specified `filename`. Additional headers can be included in the response.
+/// Defines a GET endpoint. When a GET request matches the specified `path`, the `handler_function` is invoked to generate a response based on the content of the specified `filename`, or on the entry of a `ContentType::Store` named by the rest of the path (the whole store for `path` itself). Additional headers can be included in the response. A `{{ flash }}` placeholder in the content is replaced with any flash messages queued before a redirect, `{{ csrf_field }}` with a hidden CSRF token field, and `{{ csp_nonce }}` with the request's Content-Security-Policy nonce.
 ///
 /// # Parameters
 /// - `path`: The path prefix for which the endpoint is defined.
@@ -32,6 +198,7 @@ macro_rules! get {
     ($path:expr, $name:ident => $content:expr, $headers:expr) => {
         pub fn $name(path: &str, query: Option<&str>, _: Option<&str>) -> Option<String> {
             if path.starts_with($path) {
+                $crate::__record_route($path);
                 let mut content = match $content {
                     ContentType::File(filename) => {
                         match std::fs::read_to_string(filename) {
@@ -40,6 +207,10 @@ macro_rules! get {
                         }
                     }
                     ContentType::String(str_content) => str_content.to_owned(),
+                    ContentType::Store(store) => match $crate::__store_get(store, $path, path) {
+                        Some(content) => content,
+                        None => return None,
+                    },
                 };
 
                 // Replace query parameters if they exist
@@ -53,6 +224,11 @@ macro_rules! get {
                     }
                 }
 
+                // Show flash messages queued before a redirect and protect forms and scripts
+                let content = $crate::render_flash(&content);
+                let content = $crate::render_csrf(&content);
+                let content = $crate::render_csp_nonce(&content);
+
                 Some(format!("{}{}", $headers, content))
             } else {
                 None
@@ -64,13 +240,16 @@ macro_rules! get {
 /// Defines a POST endpoint.
 ///
 /// # Usage
-/// ```rust
+/// ```text
 /// post!(path, handler_function => filename, content_type)
+/// post!(path, handler_function => ContentType::Store(&STORE), content_type)
 /// ```
 ///
 /// # Description
 /// Defines a POST endpoint. When a POST request matches the specified `path`, the `handler_function` is invoked to generate a response based on the content of the specified `filename`. The `content_type` parameter specifies the MIME type of the response.
 ///
+/// With a `ContentType::Store`, the body (JSON, or otherwise stored as a string) becomes a new entry under the rest of the path, or under a generated key when posting to `path` itself. The response is `201` with a `Location` header, or `409` if the entry exists.
+///
 /// # Parameters
 /// - `path`: The path prefix for which the endpoint is defined.
 /// - `handler_function`: The name of the function to handle the request.
@@ -78,9 +257,20 @@ macro_rules! get {
 /// - `content_type`: The MIME type of the response.
 #[macro_export]
 macro_rules! post {
+    ($path:expr, $name:ident => ContentType::Store($store:expr), $content_type:expr) => {
+        pub fn $name(path: &str, _query: Option<&str>, body: Option<&str>) -> Option<String> {
+            if path.starts_with($path) {
+                $crate::__record_route($path);
+                Some($crate::__store_post($store, $path, path, body.unwrap_or(""), $content_type))
+            } else {
+                None
+            }
+        }
+    };
     ($path:expr, $name:ident => $filename:expr, $content_type:expr) => {
         pub fn $name(path: &str, query: Option<&str>, body: Option<&str>) -> Option<String> {
             if path.starts_with($path) {
+                $crate::__record_route($path);
                 match std::fs::File::open($filename) {
                     Ok(mut file) => {
                         use std::io::Read;
@@ -123,13 +313,16 @@ macro_rules! post {
 /// Defines a PUT endpoint.
 ///
 /// # Usage
-/// ```rust
+/// ```text
 /// put!(path, handler_function => filename, handler, content_type)
+/// put!(path, handler_function => ContentType::Store(&STORE), content_type)
 /// ```
 ///
 /// # Description
 /// Defines a PUT endpoint. When a PUT request matches the specified `path`, the `handler_function` is invoked to generate a response based on the content of the specified `filename`. The `handler` parameter allows specifying a custom handler function for processing the request body. The `content_type` parameter specifies the MIME type of the response.
 ///
+/// With a `ContentType::Store`, the body replaces the entry under the rest of the path, answering `200`, or `201` when the entry is new.
+///
 /// # Parameters
 /// - `path`: The path prefix for which the endpoint is defined.
 /// - `handler_function`: The name of the function to handle the request.
@@ -138,9 +331,20 @@ macro_rules! post {
 /// - `content_type`: The MIME type of the response.
 #[macro_export]
 macro_rules! put {
+    ($path:expr, $name:ident => ContentType::Store($store:expr), $content_type:expr) => {
+        pub fn $name(path: &str, body: &str) -> Option<String> {
+            if path.starts_with($path) {
+                $crate::__record_route($path);
+                Some($crate::__store_put($store, $path, path, body, $content_type))
+            } else {
+                None
+            }
+        }
+    };
     ($path:expr, $name:ident => $filename:expr, $handler:expr, $content_type:expr) => {
         pub fn $name(path: &str, body: &str) -> Option<String> {
             if path.starts_with($path) {
+                $crate::__record_route($path);
                 match std::fs::File::open($filename) {
                     Ok(mut file) => {
                         use std::io::Read;
@@ -164,59 +368,39 @@ macro_rules! put {
 /// Defines a DELETE endpoint for removing data elements from a JSON file or any text-based content.
 ///
 /// # Usage
-/// ```rust
+/// ```text
 /// delete!(path, handler_function => content_type, key)
 /// ```
 ///
 /// # Description
-/// Defines a DELETE endpoint. When a DELETE request matches the specified `path`, the `handler_function` is invoked to remove an element identified by `key` from the specified content source. The `content_type` specifies whether the operation is on a JSON file or another type of text content. The operation can also be conditioned on query parameters.
+/// Defines a DELETE endpoint. When a DELETE request matches the specified `path`, the `handler_function` is invoked to remove an element identified by `key` from the specified content source. The `content_type` specifies whether the operation is on a JSON file or another type of text content. The operation can also be conditioned on query parameters: `?key=` overrides `key`, and `?id=` removes the element whose `"id"` field matches from the array `key` addresses.
+///
+/// For JSON, `key` is either a top-level property name or an RFC 6901 JSON Pointer such as `/users/3/email`. Array elements are addressed by index only; use `?id=` to remove an element by its `"id"` field. A pointer that does not resolve gives `404`, one that is malformed or cannot be applied gives `422`.
 ///
 /// # Parameters
 /// - `path`: The path prefix for which the endpoint is defined.
 /// - `handler_function`: The name of the function to handle the request.
-/// - `content_type`: The type of content being modified (`ContentType::File` for files, `ContentType::String` for in-memory strings).
-/// - `key`: The key or identifier of the element to be removed. For JSON, this would be the property name.
+/// - `content_type`: The type of content being modified (`ContentType::File` for files, `ContentType::Store` for an in-memory store, whose entry is named by the rest of the path or by a JSON Pointer in `?key=`).
+/// - `key`: The key or identifier of the element to be removed. For JSON, this would be the property name or a JSON Pointer.
 #[macro_export]
 macro_rules! delete {
     ($path:expr, $name:ident => $content_type:expr, $key:expr) => {
         pub fn $name(path: &str, query: Option<&str>) -> Option<String> {
             if path.starts_with($path) {
-                let key_to_remove = if let Some(query_params) = query {
-                    query_params.split('&')
-                        .find_map(|param| {
-                            let parts: Vec<&str> = param.split('=').collect();
-                            if parts.len() == 2 && parts[0] == "key" {
-                                Some(parts[1])
-                            } else {
-                                None
-                            }
-                        })
-                        .unwrap_or($key)
-                } else {
-                    $key
-                };
+                $crate::__record_route($path);
+                let key_to_remove = $crate::__query_param(query, "key").unwrap_or_else(|| $key.to_owned());
+                let id = $crate::__query_param(query, "id");
 
                 match $content_type {
                     ContentType::File(filename) => {
-                        let mut file_content = match std::fs::read_to_string(filename) {
-                            Ok(content) => content,
-                            Err(_) => return Some("HTTP/1.1 404 NOT FOUND\r\nContent-Type: text/plain\r\n\r\nFile not found.".to_owned()),
-                        };
-
-                        // Specific handling for JSON files
-                        if let Ok(mut json) = serde_json::from_str::<serde_json::Value>(&file_content) {
-                            json.as_object_mut().unwrap().remove(key_to_remove);
-                            file_content = serde_json::to_string(&json).unwrap();
-                            std::fs::write(filename, file_content).unwrap();
-                            Some(format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nElement '{}' removed successfully.", key_to_remove))
-                        } else {
-                            // Fallback for non-JSON content, demonstrating intent
-                            Some("HTTP/1.1 501 NOT IMPLEMENTED\r\nContent-Type: text/plain\r\n\r\nDeletion from non-JSON content not implemented.".to_owned())
-                        }
+                        Some($crate::__delete_from_json_file(filename, &key_to_remove, id.as_deref()))
                     },
                     ContentType::String(str_content) => {
-                        // Demonstration for in-memory string content, not implemented
-                        Some("HTTP/1.1 501 NOT IMPLEMENTED\r\nContent-Type: text/plain\r\n\r\nDeletion from in-memory content not implemented.".to_owned())
+                        // A string literal cannot change; mutable in-memory content lives in a `ContentType::Store`
+                        Some($crate::Problem::new(501).detail("Deletion from string content not implemented, use ContentType::Store.").response())
+                    },
+                    ContentType::Store(store) => {
+                        Some($crate::__store_delete(store, $path, path, $crate::__query_param(query, "key").as_deref()))
                     },
                 }
             } else {
@@ -226,114 +410,283 @@ macro_rules! delete {
     };
 }
 
+/// Defines a PATCH endpoint for partial updates of a JSON document.
+///
+/// # Usage
+/// ```text
+/// patch!(path, handler_function => content_type)
+/// ```
+///
+/// # Description
+/// Defines a PATCH endpoint. When a PATCH request matches the specified `path`, the `handler_function` applies the request body to the document and responds with the updated document. A body sent as `application/json-patch+json` is a JSON Patch (RFC 6902), including `test` operations; one sent as `application/merge-patch+json` or `application/json` is a JSON Merge Patch (RFC 7396). The patch applies completely or not at all, and a file is written back atomically.
+///
+/// A malformed patch gives `400`, a failed `test` gives `409`, an unsupported `Content-Type` gives `415` and a patch that does not fit the document gives `422`.
+///
+/// # Parameters
+/// - `path`: The path prefix for which the endpoint is defined.
+/// - `handler_function`: The name of the function to handle the request.
+/// - `content_type`: The document being patched (`ContentType::File` for a JSON file, `ContentType::String` for an in-memory document starting from the given JSON, kept for the life of the process, `ContentType::Store` for the store entry named by the rest of the path).
+#[macro_export]
+macro_rules! patch {
+    ($path:expr, $name:ident => $content_type:expr) => {
+        pub fn $name(path: &str, content_type: Option<&str>, body: &str) -> Option<String> {
+            if path.starts_with($path) {
+                $crate::__record_route($path);
+                match $content_type {
+                    ContentType::File(filename) => Some($crate::__patch_json_file(filename, content_type, body)),
+                    ContentType::String(initial) => {
+                        static DOCUMENT: $crate::__JsonDocument = std::sync::Mutex::new(None);
+                        Some($crate::__patch_json_value(&DOCUMENT, initial, content_type, body))
+                    }
+                    ContentType::Store(store) => Some($crate::__store_patch(store, $path, path, content_type, body)),
+                }
+            } else {
+                None
+            }
+        }
+    };
+}
+
+/// Defines a REST collection backed by a JSON file.
+///
+/// # Usage
+/// ```text
+/// json_resource!(path, module_name => filename)
+/// ```
+///
+/// # Description
+/// Generates a module named `module_name` with `get`, `post`, `put`, `patch` and `delete` functions that serve a full CRUD collection at `path`: `GET path`, `GET path/{id}`, `POST path`, `PUT path/{id}`, `PATCH path/{id}` and `DELETE path/{id}`. The functions match the `Handler` signatures and return `None` for other paths, so they can be mounted directly or combined with other routes. See `JsonResource` for the file layout, id generation and status codes.
+///
+/// # Parameters
+/// - `path`: The path of the collection, e.g. `"/items"`.
+/// - `module_name`: The name of the generated module.
+/// - `filename`: The JSON file holding the collection.
+#[macro_export]
+macro_rules! json_resource {
+    ($path:expr, $name:ident => $filename:expr) => {
+        pub mod $name {
+            pub fn resource() -> $crate::JsonResource {
+                $crate::JsonResource::new($path, $filename)
+            }
+
+            pub fn get(path: &str, query: Option<&str>) -> Option<String> {
+                resource().get(path, query)
+            }
+
+            pub fn post(path: &str, query: Option<&str>, body: &str) -> Option<String> {
+                resource().post(path, query, body)
+            }
+
+            pub fn put(path: &str, body: &str) -> Option<String> {
+                resource().put(path, body)
+            }
+
+            pub fn patch(path: &str, content_type: Option<&str>, body: &str) -> Option<String> {
+                resource().patch(path, content_type, body)
+            }
+
+            pub fn delete(path: &str, query: Option<&str>) -> Option<String> {
+                resource().delete(path, query)
+            }
+        }
+    };
+}
+
+/// Function signature for GET and DELETE handlers: `(path, query)`.
+pub type QueryHandler = fn(&str, Option<&str>) -> Option<String>;
+/// Function signature for POST handlers: `(path, query, body)`.
+pub type BodyHandler = fn(&str, Option<&str>, &str) -> Option<String>;
+/// Function signature for PATCH handlers: `(path, content_type, body)`.
+pub type PatchHandler = fn(&str, Option<&str>, &str) -> Option<String>;
+
 /// Represents a handler for processing HTTP requests.
 ///
-/// Contains optional functions for handling GET, POST, and PUT requests.
-#[derive(Copy, Clone)]
+/// Contains optional functions for handling GET, POST, PUT, DELETE and PATCH requests. Unset
+/// handlers are `None`, so a literal can end with `..Default::default()` to fill in the rest.
+#[derive(Copy, Clone, Default)]
 pub struct Handler {
-    pub get_handler: Option<fn(&str, Option<&str>) -> Option<String>>,
-    pub post_handler: Option<fn(&str, Option<&str>, &str) -> Option<String>>,
+    pub get_handler: Option<QueryHandler>,
+    pub post_handler: Option<BodyHandler>,
     pub put_handler: Option<fn(&str, &str) -> Option<String>>,
-    pub delete_handler: Option<fn(&str, Option<&str>) -> Option<String>>,
+    pub delete_handler: Option<QueryHandler>,
+    pub patch_handler: Option<PatchHandler>,
 }
 
 impl Handler {
+    /// The methods this handler serves, for an `Allow` header.
+    fn allowed_methods(&self) -> String {
+        let methods = [
+            ("GET", self.get_handler.is_some()),
+            ("POST", self.post_handler.is_some()),
+            ("PUT", self.put_handler.is_some()),
+            ("DELETE", self.delete_handler.is_some()),
+            ("PATCH", self.patch_handler.is_some()),
+        ];
+        methods.iter().filter(|(_, served)| *served).map(|(method, _)| *method).collect::<Vec<_>>().join(", ")
+    }
+
     pub fn handle_request(&self, request: &str) -> Option<String> {
-        println!("Received request: {}", request); // Debug print
-        let mut lines = request.lines();
-        let request_line = lines.next()?;
-        let mut parts = request_line.split(' ');
-
-        let (method, path, query) = match (parts.next(), parts.next()) {
-            (Some(method), Some(path)) => {
-                let query_start = path.find('?');
-                let (path_without_query, query) = match query_start {
-                    Some(i) => (&path[..i], Some(&path[i + 1..])),
-                    None => (path, None),
-                };
-                (method, path_without_query, query)
+        debug!("Received request: {}", request.lines().next().unwrap_or("")); // Debug print
+        let request_line = request.lines().next()?;
+        // Everything after the blank line, so multi-line bodies such as pretty-printed JSON arrive whole
+        let body = request.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+
+        let target = span!("parse").in_scope(|| {
+            let mut parts = request_line.split(' ');
+            match (parts.next(), parts.next()) {
+                (Some(method), Some(path)) => {
+                    let query_start = path.find('?');
+                    let (path_without_query, query) = match query_start {
+                        Some(i) => (&path[..i], Some(&path[i + 1..])),
+                        None => (path, None),
+                    };
+                    Some((method, path_without_query, query))
+                }
+                _ => None,
             }
-            _ => return Some("HTTP/1.1 400 BAD REQUEST\r\n\r\n".to_owned()),
+        });
+        let (method, path, query) = match target {
+            Some(target) => target,
+            None => return Some(Problem::new(400).detail("Malformed request line.").response()),
         };
 
+        let _route = span!("route").entered();
+        let handler_span = span!("handler");
+
         match method {
             "GET" => {
                 if let Some(handler) = self.get_handler {
-                    handler(path, query)
+                    handler_span.in_scope(|| handler(path, query))
                 } else {
-                    Some("HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned())
+                    Some(Problem::new(404).instance(path).response())
                 }
             }
             "POST" => {
-                let mut body = "";
-                for line in lines {
-                    if line == "\r" {
-                        break;
-                    } else {
-                        body = line;
-                    }
-                }
                 if let Some(handler) = self.post_handler {
-                    handler(path, query, body)
+                    handler_span.in_scope(|| handler(path, query, body))
                 } else {
-                    Some("HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned())
+                    Some(Problem::new(404).instance(path).response())
                 }
             }
             "PUT" => {
                 if let Some(handler) = self.put_handler {
-                    let mut body = "";
-                    for line in lines {
-                        if line == "\r" {
-                            break;
-                        } else {
-                            body = line;
-                        }
-                    }
-                    handler(path, body)
+                    handler_span.in_scope(|| handler(path, body))
                 } else {
-                    Some("HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned())
+                    Some(Problem::new(404).instance(path).response())
                 }
             }
             "DELETE" => {
                 if let Some(handler) = self.delete_handler {
-                    handler(path, query)
+                    handler_span.in_scope(|| handler(path, query))
                 } else {
-                    Some("HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned())
+                    Some(Problem::new(404).instance(path).response())
                 }
             }
-            _ => Some("HTTP/1.1 405 METHOD NOT ALLOWED\r\n\r\n".to_owned()),
+            "PATCH" => {
+                if let Some(handler) = self.patch_handler {
+                    handler_span.in_scope(|| handler(path, header(request, "Content-Type"), body))
+                } else {
+                    Some(Problem::new(404).instance(path).response())
+                }
+            }
+            _ => Some(Problem::new(405).header("Allow", &self.allowed_methods()).response()),
         }
     }
 }
 
-/// Asynchronously handles an incoming TCP stream containing an HTTP request.
+/// Creates the span covering one request, with `route` and `status` filled in as they become known.
+fn request_span(request: &str) -> trace::Span {
+    let (method, path, _) = request_target(request).unwrap_or(("-", "-", None));
+    span!("request", method = %method, path = %path, route = tracing::field::Empty, status = tracing::field::Empty)
+}
+
+/// Reads one HTTP request: the head, then as many body bytes as `Content-Length` announces.
 ///
-/// Parses the request, invokes the appropriate handler function based on the request method, generates a response, and sends it back over the stream.
-pub async fn handle_client(mut stream: TcpStream, handler: &Handler) -> io::Result<()> {
+/// A body with a `Content-Encoding` is decompressed and the request rewritten without that header,
+/// so handlers always see the plain body. Returns `Err` with the response to send instead when the
+/// body is too large or cannot be decoded.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Result<String, String>> {
     let mut buffer = [0; 1024];
-    let mut request = String::new(); // String to store the entire request
-    let mut body = String::new(); // String to store the request body
+    let mut data = Vec::new();
 
-    loop {
+    let head_end = loop {
         let n = stream.read(&mut buffer).await?;
         if n == 0 {
-            break; // End of stream
+            break None; // End of stream
         }
+        data.extend_from_slice(&buffer[..n]);
+        if let Some(i) = data.windows(4).position(|w| w == b"\r\n\r\n") {
+            break Some(i);
+        }
+    };
+    let head_end = match head_end {
+        Some(i) => i,
+        None => return Ok(Ok(String::from_utf8_lossy(&data).into_owned())),
+    };
 
-        request.push_str(&String::from_utf8_lossy(&buffer[..n])); // Append buffer to request
-
-        // Check if the end of the request body is reached
-        if let Some(i) = request.find("\r\n\r\n") {
-            body.push_str(&request[i + 4..]); // Append body to the body string
-            break;
+    let head = String::from_utf8_lossy(&data[..head_end]).into_owned();
+    let mut body = data.split_off(head_end + 4);
+    if let Some(length) = header(&head, "Content-Length").and_then(|l| l.parse::<usize>().ok()) {
+        if length > decompression::max_body_size() {
+            return Ok(Err(decompression::payload_too_large().render(header(&head, "Accept"))));
         }
+        while body.len() < length {
+            let n = stream.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            body.extend_from_slice(&buffer[..n]);
+        }
+        body.truncate(length);
     }
 
-    let response = match handler.handle_request(&request) {
-        Some(response) => response,
-        None => "HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned(),
+    let encoding = match header(&head, "Content-Encoding") {
+        Some(encoding) => encoding,
+        None => return Ok(Ok(format!("{}\r\n\r\n{}", head, String::from_utf8_lossy(&body)))),
+    };
+    let body = match decompression::decode_body(encoding, body) {
+        Ok(body) => body,
+        Err(problem) => return Ok(Err(problem.render(header(&head, "Accept")))),
     };
 
+    let mut lines: Vec<&str> = head
+        .split("\r\n")
+        .filter(|line| {
+            let name = line.split(':').next().unwrap_or("").trim();
+            !name.eq_ignore_ascii_case("Content-Encoding") && !name.eq_ignore_ascii_case("Content-Length")
+        })
+        .collect();
+    let length = format!("Content-Length: {}", body.len());
+    lines.push(&length);
+    Ok(Ok(format!("{}\r\n\r\n{}", lines.join("\r\n"), String::from_utf8_lossy(&body))))
+}
+
+/// Asynchronously handles an incoming TCP stream containing an HTTP request.
+///
+/// Parses the request, invokes the appropriate handler function based on the request method, generates a response, and sends it back over the stream.
+pub async fn handle_client(mut stream: TcpStream, handler: &Handler) -> io::Result<()> {
+    let request = match read_request(&mut stream).await? {
+        Ok(request) => request,
+        Err(response) => {
+            stream.write_all(response.as_bytes()).await?;
+            return stream.flush().await;
+        }
+    };
+
+    let peer = stream.peer_addr().ok();
+    let span = request_span(&request);
+    let response = context::scope(peer, || {
+        span.in_scope(|| {
+            trace::set_request_span(&span);
+            problem::remember_accept(&request);
+            match handler.handle_request(&request) {
+                Some(response) => response,
+                None => Problem::new(404).response(),
+            }
+        })
+    });
+    span.record("status", trace::status_of(&response));
+
     stream.write_all(response.as_bytes()).await?;
     stream.flush().await?;
 
@@ -342,28 +695,102 @@ pub async fn handle_client(mut stream: TcpStream, handler: &Handler) -> io::Resu
 
 /// Extracts a cookie value from an HTTP request.
 pub fn get_cookie(request: &str, name: &str) -> Option<String> {
-    let cookie_header = request.lines().find(|line| line.starts_with("Cookie: "))?;
+    CookieJar::from_request(request).get(name).map(str::to_owned)
+}
+
+/// Generates a URL-safe random token from `bytes` bytes of system randomness.
+pub(crate) fn random_token(bytes: usize) -> String {
+    use base64::Engine;
+    use ring::rand::SecureRandom;
+
+    let mut buf = vec![0u8; bytes];
+    ring::rand::SystemRandom::new().fill(&mut buf).unwrap();
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(buf)
+}
+
+/// Builds a `303 See Other` response, e.g. to redirect after a form POST.
+pub fn redirect(location: &str) -> String {
+    format!("HTTP/1.1 303 See Other\r\nLocation: {}\r\nContent-Length: 0\r\n\r\n", location)
+}
 
-    let cookie_str = cookie_header.trim_start_matches("Cookie: ").trim();
-    let cookies: Vec<_> = cookie_str.split(';').collect();
+/// Escapes text for inclusion in HTML.
+pub(crate) fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// Inserts a header into a raw HTTP response, right after the status line.
+pub fn add_header(response: &str, name: &str, value: &str) -> String {
+    match response.find("\r\n") {
+        Some(i) => format!("{}\r\n{}: {}{}", &response[..i], name, value, &response[i..]),
+        None => format!("{}\r\n{}: {}\r\n\r\n", response, name, value),
+    }
+}
+
+/// Splits the request line into method, path and query string.
+pub(crate) fn request_target(request: &str) -> Option<(&str, &str, Option<&str>)> {
+    let mut parts = request.lines().next()?.split(' ');
+    let method = parts.next()?;
+    let target = parts.next()?;
+    match target.split_once('?') {
+        Some((path, query)) => Some((method, path, Some(query))),
+        None => Some((method, target, None)),
+    }
+}
 
-    for cookie in cookies {
-        let parts: Vec<_> = cookie.trim().split('=').collect();
-        if parts.len() == 2 && parts[0] == name {
-            return Some(parts[1].to_owned());
+/// Decodes `%XX` escapes and `+` (as a space) in a URL query component.
+pub(crate) fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
         }
+        i += 1;
     }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
 
-    None
+/// Finds the value of a request header, matching the name case-insensitively.
+pub(crate) fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+    request
+        .lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            if key.trim().eq_ignore_ascii_case(name) {
+                Some(value.trim())
+            } else {
+                None
+            }
+        })
 }
 
 /// Parses a JSON string into a `serde_json::Value` object.
+///
+/// Use `json_body` instead to reject a malformed body with a `400` problem.
 // Function to parse JSON
 pub fn parse_json(body: &str) -> Option<serde_json::Value> {
     match serde_json::from_str(body) {
         Ok(value) => Some(value),
         Err(err) => {
-            eprintln!("Error parsing JSON: {}", err);
+            error!("Error parsing JSON: {}", err);
             None
         }
     }
@@ -378,19 +805,22 @@ pub fn generate_json_response(data: serde_json::Value) -> String {
 pub async fn run(addr: &str, port: u16, handler: Handler) -> io::Result<()> {
     let address = format!("{}:{}", addr, port);
     let listener = tokio::net::TcpListener::bind(&address).await?;
-    println!("Server listening on {}", address);
+    info!("Server listening on {}", address);
     
     let handler = std::sync::Arc::new(handler); // Wrap handler in an Arc for shared ownership
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let handler_clone = handler.clone(); // Clone the Arc to get a new reference for the new task
 
-        tokio::spawn(async move {
+        let span = span!("connection", peer = %peer);
+        tokio::spawn(trace::instrument(async move {
+            let _connection = metrics::ConnectionGuard::new();
+            debug!("Accepted connection from {}", peer);
             if let Err(e) = handle_client(stream, &handler_clone).await {
-                eprintln!("Failed to handle client: {}", e);
+                error!("Failed to handle client: {}", e);
             }
-        });
+        }, span));
     }
 }
 
@@ -398,19 +828,82 @@ pub async fn run(addr: &str, port: u16, handler: Handler) -> io::Result<()> {
 pub trait Middleware: Send + Sync {
     fn before(&self, request: &str) -> Option<String>;
     fn after(&self, response: &str) -> Option<String>;
+
+    /// Transforms the raw response bytes right before they are written, after every `after` has
+    /// run. Used for encodings such as compression that cannot be represented as a `String`.
+    fn encode(&self, request: &str, response: Vec<u8>) -> Vec<u8> {
+        response
+    }
+}
+
+/// Runs several middleware as one.
+///
+/// `before` runs in the order the middleware were added and `after` in reverse, so the first
+/// middleware sees the request first and the response last. When one short-circuits in `before`,
+/// the ones that already ran still get to process its response.
+#[derive(Clone, Default)]
+pub struct MiddlewareChain {
+    middleware: Vec<std::sync::Arc<dyn Middleware>>,
+}
+
+impl MiddlewareChain {
+    pub fn new() -> MiddlewareChain {
+        MiddlewareChain::default()
+    }
+
+    pub fn with(mut self, middleware: std::sync::Arc<dyn Middleware>) -> MiddlewareChain {
+        self.middleware.push(middleware);
+        self
+    }
+
+    fn after_from(&self, count: usize, response: &str) -> Option<String> {
+        let mut changed = None;
+        for middleware in self.middleware[..count].iter().rev() {
+            let current = changed.as_deref().unwrap_or(response);
+            if let Some(response) = middleware.after(current) {
+                changed = Some(response);
+            }
+        }
+        changed
+    }
+}
+
+impl Middleware for MiddlewareChain {
+    fn before(&self, request: &str) -> Option<String> {
+        for (i, middleware) in self.middleware.iter().enumerate() {
+            if let Some(response) = middleware.before(request) {
+                return Some(self.after_from(i, &response).unwrap_or(response));
+            }
+        }
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        self.after_from(self.middleware.len(), response)
+    }
+
+    fn encode(&self, request: &str, response: Vec<u8>) -> Vec<u8> {
+        self.middleware
+            .iter()
+            .rev()
+            .fold(response, |response, middleware| middleware.encode(request, response))
+    }
 }
 
 /// Basic logging middleware example.
+///
+/// Logs request and response heads at `debug` level, with credentials redacted and bodies left
+/// out. See `AccessLogMiddleware` for one-line access logs.
 pub struct LoggingMiddleware;
 
 impl Middleware for LoggingMiddleware {
     fn before(&self, request: &str) -> Option<String> {
-        println!("Received request: {}", request);
+        debug!("Received request: {}", redact_headers(request));
         None
     }
 
     fn after(&self, response: &str) -> Option<String> {
-        println!("Sending response: {}", response);
+        debug!("Sending response: {}", redact_headers(response));
         None
     }
 }
@@ -428,27 +921,52 @@ macro_rules! route {
 }
 
 /// JSON Web Token (JWT) authentication middleware.
+///
+/// Accepts either one of a fixed list of tokens or, when a key set is attached, any JWT whose
+/// signature verifies against it.
 pub struct AuthMiddleware {
     valid_tokens: Vec<String>, // Could be a more complex structure for scalability
+    jwks: Option<std::sync::Arc<Jwks>>,
 }
 
 impl AuthMiddleware {
     pub fn new(valid_tokens: Vec<String>) -> AuthMiddleware {
         AuthMiddleware {
             valid_tokens,
+            jwks: None,
+        }
+    }
+
+    /// Creates middleware that verifies JWTs against a (rotating) JSON Web Key Set.
+    pub fn with_jwks(jwks: std::sync::Arc<Jwks>) -> AuthMiddleware {
+        AuthMiddleware {
+            valid_tokens: Vec::new(),
+            jwks: Some(jwks),
+        }
+    }
+}
+
+impl Authenticator for AuthMiddleware {
+    fn authenticate(&self, request: &str) -> Option<Identity> {
+        let value = header(request, "Authorization")?;
+        let (scheme, token) = value.split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Bearer") {
+            return None;
         }
+        let token = token.trim();
+
+        if self.valid_tokens.iter().any(|valid| valid == token) {
+            return Some(Identity::default());
+        }
+        self.jwks.as_ref()?.verify(token).map(Identity::from_claims)
     }
 }
 
 impl Middleware for AuthMiddleware {
     fn before(&self, request: &str) -> Option<String> {
-        let token = request.lines()
-            .find(|line| line.starts_with("Authorization: Bearer "))
-            .and_then(|header| Some(header.replace("Authorization: Bearer ", "")));
-
-        match token {
-            Some(t) if self.valid_tokens.contains(&t) => None,
-            _ => Some("HTTP/1.1 401 Unauthorized\r\n\r\n".to_owned()),
+        match self.authenticate(request) {
+            Some(_) => None,
+            None => Some(Problem::new(401).header("WWW-Authenticate", "Bearer").response()),
         }
     }
 
@@ -463,36 +981,45 @@ pub fn serve_static(path: &str) -> Option<String> {
     let static_file_path = format!("static/{}", path);
     match std::fs::read_to_string(static_file_path) {
         Ok(content) => Some(format!("HTTP/1.1 200 OK\r\nContent-Type: text/html\r\n\r\n{}", content)),
-        Err(_) => Some("HTTP/1.1 404 NOT FOUND\r\n\r\n".to_owned()),
+        Err(_) => Some(Problem::new(404).instance(path).response()),
     }
 }
 
 /// Integrating middleware into the request handling process.
 pub async fn handle_client_with_middleware(mut stream: TcpStream, handler: &Handler, middleware: &dyn Middleware) -> io::Result<()> {
     // Similar to handle_client, but with middleware invocation
-    let mut buffer = [0; 1024];
-    let mut request = String::new();
+    let request = match read_request(&mut stream).await? {
+        Ok(request) => request,
+        Err(response) => {
+            stream.write_all(response.as_bytes()).await?;
+            return stream.flush().await;
+        }
+    };
 
-    loop {
-        let n = stream.read(&mut buffer).await?;
-        if n == 0 { break; }
+    let peer = stream.peer_addr().ok();
 
-        request.push_str(&String::from_utf8_lossy(&buffer[..n]));
-        if request.contains("\r\n\r\n") { break; }
-    }
+    // Middleware and handler share one request context, so they run without awaiting in between
+    let span = request_span(&request);
+    let response = context::scope(peer, || {
+        span.in_scope(|| {
+            trace::set_request_span(&span);
+            problem::remember_accept(&request);
 
-    // Invoke the before middleware function
-    if let Some(response) = middleware.before(&request) {
-        stream.write_all(response.as_bytes()).await?;
-        return Ok(());
-    }
+            // Invoke the before middleware function
+            if let Some(response) = span!("middleware.before").in_scope(|| middleware.before(&request)) {
+                return response;
+            }
 
-    let response = handler.handle_request(&request).unwrap_or_else(|| "HTTP/1.1 500 Internal Server Error\r\n\r\n".to_owned());
+            let response = handler.handle_request(&request).unwrap_or_else(|| Problem::new(404).response());
 
-    // Invoke the after middleware function
-    let response = middleware.after(&response).unwrap_or(response);
+            // Invoke the after middleware function
+            span!("middleware.after").in_scope(|| middleware.after(&response)).unwrap_or(response)
+        })
+    });
+    span.record("status", trace::status_of(&response));
+    let response = middleware.encode(&request, response.into_bytes());
 
-    stream.write_all(response.as_bytes()).await?;
+    stream.write_all(&response).await?;
     stream.flush().await?;
 
     Ok(())
@@ -502,19 +1029,22 @@ pub async fn handle_client_with_middleware(mut stream: TcpStream, handler: &Hand
 pub async fn run_with_middleware(addr: &str, port: u16, handler: Handler, middleware: std::sync::Arc<dyn Middleware>) -> io::Result<()> {
     let address = format!("{}:{}", addr, port);
     let listener = tokio::net::TcpListener::bind(&address).await?;
-    println!("Server listening on {}", address);
+    info!("Server listening on {}", address);
 
     let handler = std::sync::Arc::new(handler);
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let (stream, peer) = listener.accept().await?;
         let handler_clone = handler.clone();
         let middleware_clone = middleware.clone(); // Clone Arc for the new task
 
-        tokio::spawn(async move {
+        let span = span!("connection", peer = %peer);
+        tokio::spawn(trace::instrument(async move {
+            let _connection = metrics::ConnectionGuard::new();
+            debug!("Accepted connection from {}", peer);
             if let Err(e) = handle_client_with_middleware(stream, &handler_clone, &*middleware_clone).await {
-                eprintln!("Failed to handle client: {}", e);
+                error!("Failed to handle client: {}", e);
             }
-        });
+        }, span));
     }
 }
\ No newline at end of file
@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::{context, request_target, Middleware};
+
+/// Connections currently open in `run` and `run_with_middleware`.
+static OPEN_CONNECTIONS: AtomicI64 = AtomicI64::new(0);
+
+/// Counts a connection as open for as long as it is alive.
+pub(crate) struct ConnectionGuard;
+
+impl ConnectionGuard {
+    pub(crate) fn new() -> ConnectionGuard {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// The route pattern a route macro matched for the current request.
+pub(crate) struct MatchedRoute(pub(crate) String);
+
+const LATENCY_BUCKETS: &[f64] = &[0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+const SIZE_BUCKETS: &[f64] = &[100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0, 10_000_000.0];
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Histogram {
+        Histogram {
+            buckets: vec![0; bounds.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, bounds: &[f64], value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str, bounds: &[f64]) {
+        for (count, bound) in self.buckets.iter().zip(bounds) {
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, count);
+        }
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, self.count);
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, self.sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, self.count);
+    }
+}
+
+struct Series {
+    requests: u64,
+    latency: Histogram,
+    size: Histogram,
+}
+
+/// What `after` needs to know about the request.
+struct Started {
+    at: Instant,
+    method: String,
+}
+
+/// Middleware that records request metrics and serves them in the Prometheus text format.
+///
+/// Requests are labelled by method, route pattern (as given to the route macros, or `unmatched`)
+/// and status class (`2xx`, `4xx`, ...). Scrape the metrics at `/metrics`, or another path set
+/// with [`MetricsMiddleware::path`]. Put it first in a `MiddlewareChain` so requests rejected by
+/// other middleware are counted too.
+pub struct MetricsMiddleware {
+    path: String,
+    series: Mutex<BTreeMap<(String, String, String), Series>>,
+    in_flight: AtomicI64,
+}
+
+impl Default for MetricsMiddleware {
+    fn default() -> Self {
+        MetricsMiddleware::new()
+    }
+}
+
+impl MetricsMiddleware {
+    pub fn new() -> MetricsMiddleware {
+        MetricsMiddleware {
+            path: "/metrics".to_owned(),
+            series: Mutex::new(BTreeMap::new()),
+            in_flight: AtomicI64::new(0),
+        }
+    }
+
+    /// Serves the metrics on a different path.
+    pub fn path(mut self, path: &str) -> MetricsMiddleware {
+        self.path = path.to_owned();
+        self
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let series = self.series.lock().unwrap();
+
+        out.push_str("# HELP parfait_http_requests_total Total number of HTTP requests.\n");
+        out.push_str("# TYPE parfait_http_requests_total counter\n");
+        for (key, s) in series.iter() {
+            let _ = writeln!(out, "parfait_http_requests_total{{{}}} {}", labels(key), s.requests);
+        }
+
+        out.push_str("# HELP parfait_http_request_duration_seconds HTTP request latency.\n");
+        out.push_str("# TYPE parfait_http_request_duration_seconds histogram\n");
+        for (key, s) in series.iter() {
+            s.latency.render(&mut out, "parfait_http_request_duration_seconds", &labels(key), LATENCY_BUCKETS);
+        }
+
+        out.push_str("# HELP parfait_http_response_size_bytes HTTP response body size.\n");
+        out.push_str("# TYPE parfait_http_response_size_bytes histogram\n");
+        for (key, s) in series.iter() {
+            s.size.render(&mut out, "parfait_http_response_size_bytes", &labels(key), SIZE_BUCKETS);
+        }
+
+        out.push_str("# HELP parfait_http_requests_in_flight HTTP requests currently being processed.\n");
+        out.push_str("# TYPE parfait_http_requests_in_flight gauge\n");
+        let _ = writeln!(out, "parfait_http_requests_in_flight {}", self.in_flight.load(Ordering::Relaxed));
+
+        out.push_str("# HELP parfait_open_connections Open client connections.\n");
+        out.push_str("# TYPE parfait_open_connections gauge\n");
+        let _ = writeln!(out, "parfait_open_connections {}", OPEN_CONNECTIONS.load(Ordering::Relaxed));
+        out
+    }
+}
+
+fn labels((method, route, status): &(String, String, String)) -> String {
+    format!(
+        "method=\"{}\",route=\"{}\",status=\"{}\"",
+        escape_label(method),
+        escape_label(route),
+        status
+    )
+}
+
+/// The `method` label for a request. Other methods share one label so clients cannot create
+/// unbounded series.
+fn method_label(method: &str) -> &'static str {
+    match method {
+        "GET" => "GET",
+        "HEAD" => "HEAD",
+        "POST" => "POST",
+        "PUT" => "PUT",
+        "PATCH" => "PATCH",
+        "DELETE" => "DELETE",
+        "OPTIONS" => "OPTIONS",
+        _ => "other",
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+impl Middleware for MetricsMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, path, _) = request_target(request)?;
+        if method == "GET" && path == self.path {
+            return Some(format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\n\r\n{}",
+                self.render()
+            ));
+        }
+
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        context::insert(Started {
+            at: Instant::now(),
+            method: method_label(method).to_owned(),
+        });
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let started = context::remove::<Started>()?;
+        self.in_flight.fetch_sub(1, Ordering::Relaxed);
+
+        let route = context::with(|route: &mut MatchedRoute| route.0.clone()).unwrap_or_else(|| "unmatched".to_owned());
+        let status = match response.split(' ').nth(1).and_then(|s| s.chars().next()) {
+            Some(class @ '1'..='5') => format!("{}xx", class),
+            _ => "unknown".to_owned(),
+        };
+        let size = response.split_once("\r\n\r\n").map_or(0, |(_, body)| body.len());
+
+        let mut series = self.series.lock().unwrap();
+        let s = series.entry((started.method, route, status)).or_insert_with(|| Series {
+            requests: 0,
+            latency: Histogram::new(LATENCY_BUCKETS),
+            size: Histogram::new(SIZE_BUCKETS),
+        });
+        s.requests += 1;
+        s.latency.observe(LATENCY_BUCKETS, started.at.elapsed().as_secs_f64());
+        s.size.observe(SIZE_BUCKETS, size as f64);
+        None
+    }
+}
@@ -0,0 +1,286 @@
+use serde_json::{Map, Value};
+
+use crate::json_pointer::{self, PointerError};
+use crate::Problem;
+
+/// The patch formats PATCH routes accept, for the `Accept-Patch` header.
+pub(crate) const ACCEPT_PATCH: &str = "application/json-patch+json, application/merge-patch+json";
+
+/// Why a patch could not be applied, following the status codes suggested by RFC 5789.
+#[derive(Debug)]
+pub(crate) enum PatchError {
+    /// The patch document is malformed.
+    Malformed(String),
+    /// The request's `Content-Type` is not a supported patch format.
+    UnsupportedMediaType(String),
+    /// The patch is well-formed but cannot be applied to the document.
+    Unprocessable(String),
+    /// A `test` operation failed.
+    Conflict(String),
+}
+
+impl From<PatchError> for Problem {
+    fn from(e: PatchError) -> Problem {
+        match e {
+            PatchError::Malformed(message) => Problem::new(400).detail(&message),
+            PatchError::UnsupportedMediaType(message) => {
+                Problem::new(415).detail(&message).header("Accept-Patch", ACCEPT_PATCH)
+            }
+            PatchError::Unprocessable(message) => Problem::new(422).detail(&message),
+            PatchError::Conflict(message) => Problem::new(409).detail(&message),
+        }
+    }
+}
+
+impl From<PointerError> for PatchError {
+    fn from(e: PointerError) -> Self {
+        match e {
+            PointerError::Invalid(message) => PatchError::Unprocessable(message),
+            PointerError::NotFound(at) => PatchError::Unprocessable(format!("{} does not exist", at)),
+        }
+    }
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to `target`.
+pub(crate) fn merge_patch(target: &mut Value, patch: &Value) {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        _ => {
+            *target = patch.clone();
+            return;
+        }
+    };
+    if !target.is_object() {
+        *target = Value::Object(Map::new());
+    }
+    let target = target.as_object_mut().unwrap();
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.clone()).or_insert(Value::Null), value);
+        }
+    }
+}
+
+/// Applies a JSON Patch (RFC 6902) to `document`. Either every operation applies or none does.
+pub(crate) fn json_patch(document: &mut Value, patch: &Value) -> Result<(), PatchError> {
+    let operations = patch
+        .as_array()
+        .ok_or_else(|| PatchError::Malformed("a JSON Patch must be an array of operations".to_owned()))?;
+
+    let mut patched = document.clone();
+    for (i, operation) in operations.iter().enumerate() {
+        apply_operation(&mut patched, operation).map_err(|e| match e {
+            PatchError::Malformed(m) => PatchError::Malformed(format!("operation {}: {}", i, m)),
+            PatchError::UnsupportedMediaType(m) => PatchError::UnsupportedMediaType(m),
+            PatchError::Unprocessable(m) => PatchError::Unprocessable(format!("operation {}: {}", i, m)),
+            PatchError::Conflict(m) => PatchError::Conflict(format!("operation {}: {}", i, m)),
+        })?;
+    }
+    *document = patched;
+    Ok(())
+}
+
+/// Applies a patch body to `document`, picking the format from the request's `Content-Type`.
+///
+/// `application/json-patch+json` is a JSON Patch; `application/merge-patch+json` and plain
+/// `application/json` are a JSON Merge Patch.
+pub(crate) fn apply(document: &mut Value, content_type: Option<&str>, body: &str) -> Result<(), PatchError> {
+    let media_type = content_type
+        .and_then(|t| t.split(';').next())
+        .map(|t| t.trim().to_ascii_lowercase())
+        .unwrap_or_default();
+    let json_patch_format = match media_type.as_str() {
+        "application/json-patch+json" => true,
+        "application/merge-patch+json" | "application/json" => false,
+        "" => return Err(PatchError::UnsupportedMediaType("a patch needs a Content-Type".to_owned())),
+        other => {
+            return Err(PatchError::UnsupportedMediaType(format!("'{}' is not a supported patch format", other)))
+        }
+    };
+
+    let patch: Value =
+        serde_json::from_str(body).map_err(|e| PatchError::Malformed(format!("invalid JSON: {}", e)))?;
+    if json_patch_format {
+        json_patch(document, &patch)
+    } else {
+        merge_patch(document, &patch);
+        Ok(())
+    }
+}
+
+fn member<'o>(operation: &'o Value, name: &str) -> Result<&'o Value, PatchError> {
+    operation
+        .get(name)
+        .ok_or_else(|| PatchError::Malformed(format!("missing \"{}\"", name)))
+}
+
+fn pointer_member<'o>(operation: &'o Value, name: &str) -> Result<&'o str, PatchError> {
+    member(operation, name)?
+        .as_str()
+        .ok_or_else(|| PatchError::Malformed(format!("\"{}\" must be a string", name)))
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), PatchError> {
+    let op = pointer_member(operation, "op")?;
+    let path = pointer_member(operation, "path")?;
+    match op {
+        "add" => add(document, path, member(operation, "value")?.clone()),
+        "remove" => json_pointer::remove(document, path).map(drop).map_err(PatchError::from),
+        "replace" => {
+            let value = member(operation, "value")?.clone();
+            let tokens = json_pointer::parse(path)?;
+            *json_pointer::resolve_mut(document, &tokens)? = value;
+            Ok(())
+        }
+        "move" => {
+            let from = pointer_member(operation, "from")?;
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(PatchError::Unprocessable(format!("cannot move {} into its own child {}", from, path)));
+            }
+            let value = json_pointer::remove(document, from)?;
+            add(document, path, value)
+        }
+        "copy" => {
+            let from = json_pointer::parse(pointer_member(operation, "from")?)?;
+            let value = json_pointer::resolve_mut(document, &from)?.clone();
+            add(document, path, value)
+        }
+        "test" => {
+            let expected = member(operation, "value")?;
+            let tokens = json_pointer::parse(path)?;
+            if json_pointer::resolve_mut(document, &tokens)? == expected {
+                Ok(())
+            } else {
+                Err(PatchError::Conflict(format!("test failed at {}", path)))
+            }
+        }
+        other => Err(PatchError::Malformed(format!("unknown operation '{}'", other))),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), PatchError> {
+    let tokens = json_pointer::parse(path)?;
+    let (last, parent) = match tokens.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return Ok(());
+        }
+    };
+    match json_pointer::resolve_mut(document, parent)? {
+        Value::Object(map) => {
+            map.insert(last.clone(), value);
+            Ok(())
+        }
+        Value::Array(items) => {
+            let position = if last == "-" {
+                items.len()
+            } else {
+                json_pointer::index(last)
+                    .filter(|&i| i <= items.len())
+                    .ok_or_else(|| PatchError::Unprocessable(format!("{} is not a valid array position", path)))?
+            };
+            items.insert(position, value);
+            Ok(())
+        }
+        _ => Err(PatchError::Unprocessable(format!("the parent of {} is not an object or array", path))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn patched(document: Value, patch: Value) -> Result<Value, PatchError> {
+        let mut document = document;
+        json_patch(&mut document, &patch).map(|()| document)
+    }
+
+    #[test]
+    fn applies_every_operation() {
+        let document = json!({ "a": 1, "list": [1, 2], "nested": { "x": true } });
+        let patch = json!([
+            { "op": "add", "path": "/b", "value": 2 },
+            { "op": "add", "path": "/list/1", "value": 9 },
+            { "op": "add", "path": "/list/-", "value": 3 },
+            { "op": "remove", "path": "/a" },
+            { "op": "replace", "path": "/nested/x", "value": false },
+            { "op": "move", "from": "/b", "path": "/nested/b" },
+            { "op": "copy", "from": "/list/0", "path": "/first" },
+            { "op": "test", "path": "/list", "value": [1, 9, 2, 3] },
+        ]);
+        assert_eq!(
+            patched(document, patch).unwrap(),
+            json!({ "list": [1, 9, 2, 3], "nested": { "x": false, "b": 2 }, "first": 1 })
+        );
+    }
+
+    #[test]
+    fn failed_test_leaves_document_untouched() {
+        let document = json!({ "a": 1 });
+        let patch = json!([
+            { "op": "replace", "path": "/a", "value": 2 },
+            { "op": "test", "path": "/a", "value": 1 },
+        ]);
+        let mut target = document.clone();
+        assert!(matches!(json_patch(&mut target, &patch), Err(PatchError::Conflict(_))));
+        assert_eq!(target, document);
+    }
+
+    #[test]
+    fn failed_move_leaves_document_untouched() {
+        let document = json!({ "a": { "b": 1 } });
+        let mut target = document.clone();
+        let into_child = json!([{ "op": "move", "from": "/a", "path": "/a/b/c" }]);
+        assert!(matches!(json_patch(&mut target, &into_child), Err(PatchError::Unprocessable(_))));
+        let missing = json!([{ "op": "remove", "path": "/a/b" }, { "op": "move", "from": "/x", "path": "/y" }]);
+        assert!(matches!(json_patch(&mut target, &missing), Err(PatchError::Unprocessable(_))));
+        assert_eq!(target, document);
+    }
+
+    #[test]
+    fn array_tokens_are_strict_indexes() {
+        let document = json!({ "items": [{ "id": 5 }] });
+        for patch in [
+            json!([{ "op": "test", "path": "/items/5", "value": { "id": 5 } }]),
+            json!([{ "op": "replace", "path": "/items/5", "value": 0 }]),
+            json!([{ "op": "remove", "path": "/items/1" }]),
+            json!([{ "op": "copy", "from": "/items/01", "path": "/x" }]),
+            json!([{ "op": "add", "path": "/items/2", "value": 0 }]),
+        ] {
+            assert!(matches!(patched(document.clone(), patch), Err(PatchError::Unprocessable(_))));
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_patches() {
+        let document = json!({});
+        assert!(matches!(patched(document.clone(), json!({ "op": "add" })), Err(PatchError::Malformed(_))));
+        assert!(matches!(patched(document.clone(), json!([{ "op": "add", "path": "/a" }])), Err(PatchError::Malformed(_))));
+        assert!(matches!(patched(document, json!([{ "op": "frobnicate", "path": "" }])), Err(PatchError::Malformed(_))));
+    }
+
+    #[test]
+    fn merge_patch_follows_rfc_7396() {
+        let mut document = json!({ "a": "b", "c": { "d": "e", "f": "g" } });
+        merge_patch(&mut document, &json!({ "a": "z", "c": { "f": null } }));
+        assert_eq!(document, json!({ "a": "z", "c": { "d": "e" } }));
+        merge_patch(&mut document, &json!([1]));
+        assert_eq!(document, json!([1]));
+    }
+
+    #[test]
+    fn picks_the_format_from_the_content_type() {
+        let mut document = json!({ "a": 1 });
+        assert!(apply(&mut document, Some("application/merge-patch+json"), r#"{"b":2}"#).is_ok());
+        assert!(apply(&mut document, Some("application/json-patch+json; charset=utf-8"), r#"[{"op":"remove","path":"/a"}]"#).is_ok());
+        assert_eq!(document, json!({ "b": 2 }));
+        assert!(matches!(apply(&mut document, None, "{}"), Err(PatchError::UnsupportedMediaType(_))));
+        assert!(matches!(apply(&mut document, Some("text/plain"), "{}"), Err(PatchError::UnsupportedMediaType(_))));
+        assert!(matches!(apply(&mut document, Some("application/json"), "{"), Err(PatchError::Malformed(_))));
+    }
+}
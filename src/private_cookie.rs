@@ -0,0 +1,281 @@
+use std::io;
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use ring::aead::{self, Aad, LessSafeKey, Nonce, UnboundKey};
+use ring::hmac;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::{Cookie, CookieJar};
+
+struct CookieKey {
+    signing: hmac::Key,
+    encryption: LessSafeKey,
+}
+
+impl CookieKey {
+    fn derive(secret: &[u8]) -> io::Result<CookieKey> {
+        if secret.len() < 32 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "cookie secrets must be at least 32 bytes long",
+            ));
+        }
+        // Separate keys for signing and encryption, both derived from the one server secret
+        let master = hmac::Key::new(hmac::HMAC_SHA256, secret);
+        let signing = hmac::sign(&master, b"parfait cookie signing");
+        let encryption = hmac::sign(&master, b"parfait cookie encryption");
+        Ok(CookieKey {
+            signing: hmac::Key::new(hmac::HMAC_SHA256, signing.as_ref()),
+            encryption: LessSafeKey::new(
+                UnboundKey::new(&aead::AES_256_GCM, encryption.as_ref()).unwrap(),
+            ),
+        })
+    }
+}
+
+/// Server secrets used to sign and encrypt cookies.
+///
+/// The first secret signs and encrypts new cookies. Secrets added with
+/// [`CookieKeys::previous`] are still accepted when reading, so a secret can be rotated
+/// without logging everyone out.
+pub struct CookieKeys {
+    keys: Vec<CookieKey>,
+}
+
+impl CookieKeys {
+    /// Creates the key set from a secret of at least 32 random bytes. Shorter secrets are
+    /// rejected with `InvalidInput`.
+    pub fn new(secret: &[u8]) -> io::Result<CookieKeys> {
+        Ok(CookieKeys {
+            keys: vec![CookieKey::derive(secret)?],
+        })
+    }
+
+    /// Creates the key set from a freshly generated random secret.
+    pub fn generate() -> CookieKeys {
+        let mut secret = [0u8; 64];
+        SystemRandom::new().fill(&mut secret).unwrap();
+        CookieKeys::new(&secret).unwrap()
+    }
+
+    /// Accepts cookies signed or encrypted with an older secret.
+    pub fn previous(mut self, secret: &[u8]) -> io::Result<CookieKeys> {
+        self.keys.push(CookieKey::derive(secret)?);
+        Ok(self)
+    }
+
+    fn current(&self) -> &CookieKey {
+        &self.keys[0]
+    }
+
+    fn sign(&self, name: &str, value: &str) -> String {
+        let tag = hmac::sign(&self.current().signing, format!("{}={}", name, value).as_bytes());
+        format!("{}.{}", URL_SAFE_NO_PAD.encode(tag.as_ref()), value)
+    }
+
+    fn verify(&self, name: &str, signed: &str) -> Option<String> {
+        let (tag, value) = signed.split_once('.')?;
+        let tag = URL_SAFE_NO_PAD.decode(tag).ok()?;
+        let message = format!("{}={}", name, value);
+        self.keys
+            .iter()
+            .any(|key| hmac::verify(&key.signing, message.as_bytes(), &tag).is_ok())
+            .then(|| value.to_owned())
+    }
+
+    fn encrypt(&self, name: &str, value: &str) -> String {
+        let mut nonce = [0u8; aead::NONCE_LEN];
+        SystemRandom::new().fill(&mut nonce).unwrap();
+        let mut sealed = value.as_bytes().to_vec();
+        self.current()
+            .encryption
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(name.as_bytes()),
+                &mut sealed,
+            )
+            .unwrap();
+        let mut data = nonce.to_vec();
+        data.extend_from_slice(&sealed);
+        URL_SAFE_NO_PAD.encode(data)
+    }
+
+    fn decrypt(&self, name: &str, encrypted: &str) -> Option<String> {
+        let data = URL_SAFE_NO_PAD.decode(encrypted).ok()?;
+        if data.len() < aead::NONCE_LEN {
+            return None;
+        }
+        let (nonce, sealed) = data.split_at(aead::NONCE_LEN);
+        self.keys.iter().find_map(|key| {
+            let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+            let mut in_out = sealed.to_vec();
+            let plain = key
+                .encryption
+                .open_in_place(nonce, Aad::from(name.as_bytes()), &mut in_out)
+                .ok()?;
+            String::from_utf8(plain.to_vec()).ok()
+        })
+    }
+}
+
+/// A view of a [`CookieJar`] whose cookies are signed, so clients can read but not modify them.
+pub struct SignedJar<'a> {
+    jar: &'a mut CookieJar,
+    keys: &'a CookieKeys,
+}
+
+impl SignedJar<'_> {
+    /// Returns the value of a cookie, or `None` if it is missing or has been tampered with.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.keys.verify(name, self.jar.get(name)?)
+    }
+
+    /// Signs the cookie's value and queues it for the response.
+    pub fn add(&mut self, cookie: Cookie) {
+        let value = self.keys.sign(cookie.name(), cookie.value());
+        self.jar.add(cookie.value_of(value));
+    }
+
+    pub fn remove(&mut self, name: &str, path: &str) {
+        self.jar.remove(name, path);
+    }
+}
+
+/// A view of a [`CookieJar`] whose cookies are encrypted, so clients can neither read nor modify them.
+pub struct PrivateJar<'a> {
+    jar: &'a mut CookieJar,
+    keys: &'a CookieKeys,
+}
+
+impl PrivateJar<'_> {
+    /// Returns the decrypted value of a cookie, or `None` if it is missing or has been tampered with.
+    pub fn get(&self, name: &str) -> Option<String> {
+        self.keys.decrypt(name, self.jar.get(name)?)
+    }
+
+    /// Encrypts the cookie's value and queues it for the response.
+    pub fn add(&mut self, cookie: Cookie) {
+        let value = self.keys.encrypt(cookie.name(), cookie.value());
+        self.jar.add(cookie.value_of(value));
+    }
+
+    pub fn remove(&mut self, name: &str, path: &str) {
+        self.jar.remove(name, path);
+    }
+}
+
+impl CookieJar {
+    /// Reads and writes cookies signed with `keys`.
+    pub fn signed<'a>(&'a mut self, keys: &'a CookieKeys) -> SignedJar<'a> {
+        SignedJar { jar: self, keys }
+    }
+
+    /// Reads and writes cookies encrypted with `keys`.
+    pub fn private<'a>(&'a mut self, keys: &'a CookieKeys) -> PrivateJar<'a> {
+        PrivateJar { jar: self, keys }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &[u8] = b"0123456789abcdef0123456789abcdef";
+    const OLD_SECRET: &[u8] = b"fedcba9876543210fedcba9876543210";
+
+    /// The cookies a browser would send back after receiving `jar`'s response.
+    fn echo(jar: &CookieJar) -> CookieJar {
+        let cookies: Vec<String> = jar
+            .delta()
+            .iter()
+            .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+            .collect();
+        CookieJar::from_request(&format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookies.join("; ")))
+    }
+
+    /// `jar`'s request cookies with `name` set to `value`, as a client could send them.
+    fn with_value(jar: &CookieJar, name: &str, value: &str) -> CookieJar {
+        let mut cookies: Vec<String> = jar
+            .iter()
+            .filter(|(n, _)| *n != name)
+            .map(|(n, v)| format!("{}={}", n, v))
+            .collect();
+        cookies.push(format!("{}={}", name, value));
+        CookieJar::from_request(&format!("GET / HTTP/1.1\r\nCookie: {}\r\n\r\n", cookies.join("; ")))
+    }
+
+    #[test]
+    fn signed_and_private_cookies_round_trip() {
+        let keys = CookieKeys::new(SECRET).unwrap();
+        let mut jar = CookieJar::default();
+        jar.signed(&keys).add(Cookie::new("user", "ada"));
+        jar.private(&keys).add(Cookie::new("cart", "3 items"));
+
+        let mut received = echo(&jar);
+        assert!(received.get("user").unwrap().ends_with(".ada"));
+        assert!(!received.get("cart").unwrap().contains("items"));
+        assert_eq!(received.signed(&keys).get("user").as_deref(), Some("ada"));
+        assert_eq!(received.private(&keys).get("cart").as_deref(), Some("3 items"));
+    }
+
+    #[test]
+    fn tampered_cookies_are_rejected() {
+        let keys = CookieKeys::new(SECRET).unwrap();
+        let mut jar = CookieJar::default();
+        jar.signed(&keys).add(Cookie::new("role", "user"));
+        jar.private(&keys).add(Cookie::new("secret", "x"));
+        let received = echo(&jar);
+
+        let (tag, _) = received.get("role").unwrap().split_once('.').unwrap();
+        let mut forged = with_value(&received, "role", &format!("{}.admin", tag));
+        assert_eq!(forged.signed(&keys).get("role"), None);
+        let mut forged = with_value(&received, "role", "AAAA.user");
+        assert_eq!(forged.signed(&keys).get("role"), None);
+
+        let mut sealed = URL_SAFE_NO_PAD.decode(received.get("secret").unwrap()).unwrap();
+        *sealed.last_mut().unwrap() ^= 1;
+        let mut forged = with_value(&received, "secret", &URL_SAFE_NO_PAD.encode(sealed));
+        assert_eq!(forged.private(&keys).get("secret"), None);
+    }
+
+    #[test]
+    fn rotated_keys_read_old_cookies_and_write_new_ones() {
+        let old = CookieKeys::new(OLD_SECRET).unwrap();
+        let mut jar = CookieJar::default();
+        jar.signed(&old).add(Cookie::new("user", "ada"));
+        jar.private(&old).add(Cookie::new("cart", "3 items"));
+        let mut received = echo(&jar);
+
+        let rotated = CookieKeys::new(SECRET).unwrap().previous(OLD_SECRET).unwrap();
+        assert_eq!(received.signed(&rotated).get("user").as_deref(), Some("ada"));
+        assert_eq!(received.private(&rotated).get("cart").as_deref(), Some("3 items"));
+
+        let mut jar = CookieJar::default();
+        jar.signed(&rotated).add(Cookie::new("user", "ada"));
+        let mut received = echo(&jar);
+        assert_eq!(received.signed(&CookieKeys::new(SECRET).unwrap()).get("user").as_deref(), Some("ada"));
+        assert_eq!(received.signed(&old).get("user"), None);
+    }
+
+    #[test]
+    fn cookies_are_bound_to_their_name() {
+        let keys = CookieKeys::new(SECRET).unwrap();
+        let mut jar = CookieJar::default();
+        jar.signed(&keys).add(Cookie::new("guest", "ada"));
+        jar.private(&keys).add(Cookie::new("note", "hi"));
+        let received = echo(&jar);
+
+        let mut moved = with_value(&received, "admin", received.get("guest").unwrap());
+        assert_eq!(moved.signed(&keys).get("admin"), None);
+        let mut moved = with_value(&received, "other", received.get("note").unwrap());
+        assert_eq!(moved.private(&keys).get("other"), None);
+    }
+
+    #[test]
+    fn short_secrets_are_rejected() {
+        let error = CookieKeys::new(b"too short").err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        assert!(CookieKeys::generate().previous(&[0; 31]).is_err());
+    }
+}
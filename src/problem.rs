@@ -0,0 +1,342 @@
+use std::fmt;
+use std::io;
+
+use serde_json::{Map, Value};
+
+use crate::{context, escape_html, header};
+
+/// The `Accept` header of the request being handled, for choosing how to render problems.
+pub(crate) struct Accept(pub(crate) String);
+
+/// Remembers the current request's `Accept` header in the request context.
+pub(crate) fn remember_accept(request: &str) {
+    if let Some(accept) = header(request, "Accept") {
+        context::insert(Accept(accept.to_owned()));
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        406 => "Not Acceptable",
+        409 => "Conflict",
+        410 => "Gone",
+        412 => "Precondition Failed",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        422 => "Unprocessable Entity",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ if status < 500 => "Client Error",
+        _ => "Server Error",
+    }
+}
+
+/// The highest q-value `accept` gives any of `media_types`, counting wildcards.
+fn quality(accept: &str, media_types: &[&str]) -> f32 {
+    accept
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';');
+            let range = parts.next()?.trim().to_ascii_lowercase();
+            let q = parts
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|q| q.trim().parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let matches = media_types.iter().any(|media_type| {
+                let (kind, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+                range == *media_type || range == "*/*" || range == format!("{}/*", kind)
+            });
+            if matches {
+                Some(q)
+            } else {
+                None
+            }
+        })
+        .fold(0.0, f32::max)
+}
+
+/// Whether a client with this `Accept` header prefers an HTML page to JSON.
+fn prefers_html(accept: &str) -> bool {
+    let html = quality(accept, &["text/html", "application/xhtml+xml"]);
+    let json = quality(accept, &["application/problem+json", "application/json"]);
+    html > json
+}
+
+/// An HTTP error in the shape of RFC 7807 "Problem Details".
+///
+/// Handlers can return it with `?` from a function returning `Result<String, Problem>` and
+/// turn the result into a response with `respond`:
+///
+/// ```text
+/// fn show_item(path: &str, query: Option<&str>) -> Result<String, Problem> {
+///     let item = find(path).ok_or_else(|| Problem::new(404).detail("No such item."))?;
+///     ...
+/// }
+///
+/// get_handler: Some(|path, query| respond(show_item(path, query))),
+/// ```
+///
+/// API clients get `application/problem+json` with the `type`, `title`, `status`, `detail`,
+/// `instance` and extension members; browsers, whose `Accept` prefers `text/html`, get an HTML
+/// error page with the same information.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Problem(Box<Details>);
+
+#[derive(Clone, Debug, PartialEq)]
+struct Details {
+    kind: String,
+    title: String,
+    status: u16,
+    detail: Option<String>,
+    instance: Option<String>,
+    extensions: Map<String, Value>,
+    headers: Vec<(String, String)>,
+}
+
+impl Problem {
+    /// A problem with `status`, titled with the status' reason phrase.
+    pub fn new(status: u16) -> Problem {
+        Problem(Box::new(Details {
+            kind: "about:blank".to_owned(),
+            title: reason_phrase(status).to_owned(),
+            status,
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+            headers: Vec::new(),
+        }))
+    }
+
+    /// A URI identifying the problem type. Defaults to `about:blank`.
+    pub fn with_type(mut self, uri: &str) -> Problem {
+        self.0.kind = uri.to_owned();
+        self
+    }
+
+    /// A short summary of the problem type.
+    pub fn title(mut self, title: &str) -> Problem {
+        self.0.title = title.to_owned();
+        self
+    }
+
+    /// An explanation specific to this occurrence of the problem.
+    pub fn detail(mut self, detail: &str) -> Problem {
+        self.0.detail = Some(detail.to_owned());
+        self
+    }
+
+    /// A URI identifying this occurrence of the problem, such as the request path.
+    pub fn instance(mut self, uri: &str) -> Problem {
+        self.0.instance = Some(uri.to_owned());
+        self
+    }
+
+    /// Adds an extension member to the JSON body.
+    pub fn extension(mut self, name: &str, value: impl Into<Value>) -> Problem {
+        self.0.extensions.insert(name.to_owned(), value.into());
+        self
+    }
+
+    /// Adds a response header, e.g. `Allow` for a `405`.
+    pub fn header(mut self, name: &str, value: &str) -> Problem {
+        self.0.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn status(&self) -> u16 {
+        self.0.status
+    }
+
+    /// The problem as an `application/problem+json` document.
+    pub fn to_json(&self) -> Value {
+        let mut document = Map::new();
+        document.insert("type".to_owned(), Value::from(self.0.kind.as_str()));
+        document.insert("title".to_owned(), Value::from(self.0.title.as_str()));
+        document.insert("status".to_owned(), Value::from(self.0.status));
+        if let Some(detail) = &self.0.detail {
+            document.insert("detail".to_owned(), Value::from(detail.as_str()));
+        }
+        if let Some(instance) = &self.0.instance {
+            document.insert("instance".to_owned(), Value::from(instance.as_str()));
+        }
+        for (name, value) in &self.0.extensions {
+            document.entry(name.clone()).or_insert_with(|| value.clone());
+        }
+        Value::Object(document)
+    }
+
+    /// The problem as an HTML error page.
+    pub fn to_html(&self) -> String {
+        let mut page = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{status} {title}</title></head>\n<body>\n<h1>{status} {title}</h1>\n",
+            status = self.0.status,
+            title = escape_html(&self.0.title)
+        );
+        if let Some(detail) = &self.0.detail {
+            page.push_str(&format!("<p>{}</p>\n", escape_html(detail)));
+        }
+        if !self.0.extensions.is_empty() {
+            page.push_str("<dl>\n");
+            for (name, value) in &self.0.extensions {
+                let value = match value {
+                    Value::String(value) => value.clone(),
+                    value => value.to_string(),
+                };
+                page.push_str(&format!("<dt>{}</dt><dd>{}</dd>\n", escape_html(name), escape_html(&value)));
+            }
+            page.push_str("</dl>\n");
+        }
+        page.push_str("</body>\n</html>\n");
+        page
+    }
+
+    /// Renders the full HTTP response for a client sending this `Accept` header.
+    pub fn render(&self, accept: Option<&str>) -> String {
+        let (content_type, body) = if accept.is_some_and(prefers_html) {
+            ("text/html; charset=utf-8", self.to_html())
+        } else {
+            ("application/problem+json", self.to_json().to_string())
+        };
+        let mut response = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\n",
+            self.0.status,
+            reason_phrase(self.0.status).to_ascii_uppercase(),
+            content_type
+        );
+        for (name, value) in &self.0.headers {
+            response.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        response.push_str("\r\n");
+        response.push_str(&body);
+        response
+    }
+
+    /// Renders the response for the request being handled, honouring its `Accept` header.
+    pub fn response(&self) -> String {
+        let accept = context::with(|accept: &mut Accept| accept.0.clone());
+        self.render(accept.as_deref())
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0.detail {
+            Some(detail) => write!(f, "{} {}: {}", self.0.status, self.0.title, detail),
+            None => write!(f, "{} {}", self.0.status, self.0.title),
+        }
+    }
+}
+
+impl std::error::Error for Problem {}
+
+impl From<Problem> for String {
+    fn from(problem: Problem) -> String {
+        problem.response()
+    }
+}
+
+impl From<io::Error> for Problem {
+    fn from(e: io::Error) -> Problem {
+        match e.kind() {
+            io::ErrorKind::NotFound => Problem::new(404),
+            io::ErrorKind::PermissionDenied => Problem::new(403),
+            _ => {
+                // Server-side failures are logged, not shown to the client
+                error!("I/O error: {}", e);
+                Problem::new(500)
+            }
+        }
+    }
+}
+
+impl From<serde_json::Error> for Problem {
+    fn from(e: serde_json::Error) -> Problem {
+        Problem::new(400).detail(&format!("invalid JSON: {}", e))
+    }
+}
+
+/// Turns a handler result into a response, rendering a `Problem` for the current request.
+pub fn respond<T: Into<Option<String>>>(result: Result<T, Problem>) -> Option<String> {
+    match result {
+        Ok(response) => response.into(),
+        Err(problem) => Some(problem.response()),
+    }
+}
+
+/// Parses a JSON request body, failing with a `400` problem.
+pub fn json_body(body: &str) -> Result<Value, Problem> {
+    Ok(serde_json::from_str(body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negotiates_html_for_browsers_only() {
+        assert!(prefers_html("text/html,application/xhtml+xml,application/xml;q=0.9,*/*;q=0.8"));
+        assert!(!prefers_html("application/json"));
+        assert!(!prefers_html("*/*"));
+        assert!(!prefers_html("text/html;q=0.5, application/problem+json"));
+        assert!(prefers_html("text/*"));
+    }
+
+    #[test]
+    fn renders_problem_json_with_headers() {
+        let problem = Problem::new(405)
+            .instance("/items")
+            .extension("allowed", vec!["GET"])
+            .header("Allow", "GET");
+        let response = problem.render(None);
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert_eq!(
+            head,
+            "HTTP/1.1 405 METHOD NOT ALLOWED\r\nContent-Type: application/problem+json\r\nAllow: GET"
+        );
+        let body: Value = serde_json::from_str(body).unwrap();
+        assert_eq!(
+            body,
+            serde_json::json!({
+                "type": "about:blank",
+                "title": "Method Not Allowed",
+                "status": 405,
+                "instance": "/items",
+                "allowed": ["GET"],
+            })
+        );
+    }
+
+    #[test]
+    fn extensions_cannot_override_standard_members() {
+        let problem = Problem::new(404).extension("status", 200);
+        assert_eq!(problem.to_json()["status"], 404);
+    }
+
+    #[test]
+    fn html_pages_are_escaped() {
+        let response = Problem::new(400).detail("<script>").render(Some("text/html"));
+        assert!(response.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(response.contains("<p>&lt;script&gt;</p>"));
+    }
+
+    #[test]
+    fn question_mark_converts_errors() {
+        fn parse() -> Result<String, Problem> {
+            let value = json_body("{")?;
+            Ok(value.to_string())
+        }
+        assert_eq!(parse().unwrap_err().status(), 400);
+        let missing: Problem = io::Error::from(io::ErrorKind::NotFound).into();
+        assert_eq!(missing.status(), 404);
+        assert!(respond(parse()).unwrap().starts_with("HTTP/1.1 400 BAD REQUEST"));
+    }
+}
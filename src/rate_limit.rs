@@ -0,0 +1,314 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{add_header, context, request_target, Authenticator, Middleware, Problem};
+
+type KeyFn = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
+
+/// How clients are told apart for rate limiting.
+enum KeySource {
+    Peer,
+    Identity(Arc<dyn Authenticator>),
+    Custom(KeyFn),
+}
+
+/// A token bucket: `capacity` requests at once, refilled at `capacity` per `period`.
+#[derive(Clone, Copy, Debug)]
+struct Limit {
+    capacity: u32,
+    period: Duration,
+}
+
+impl Limit {
+    fn new(capacity: u32, period: Duration) -> Limit {
+        assert!(capacity > 0, "a rate limit must allow at least one request");
+        assert!(!period.is_zero(), "a rate limit period must not be zero");
+        Limit { capacity, period }
+    }
+
+    fn refill_per_sec(&self) -> f64 {
+        self.capacity as f64 / self.period.as_secs_f64()
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// The quota state reported in `RateLimit-*` headers.
+struct Status {
+    limit: u32,
+    remaining: u32,
+    reset: u64,
+}
+
+/// Token-bucket rate limiting middleware.
+///
+/// Clients are keyed by peer address by default. Each matching route prefix has its own bucket
+/// per client; other requests share the default bucket. Rejected requests get
+/// `429 Too Many Requests` with `Retry-After`, and every response carries the draft
+/// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset` headers.
+///
+/// At most `max_keys` buckets are kept; when full, idle buckets and then the least recently
+/// used ones are dropped.
+///
+/// Limits must allow at least one request per non-zero period; `new` and `route` panic
+/// otherwise.
+pub struct RateLimitMiddleware {
+    key: KeySource,
+    default: Option<Limit>,
+    routes: Vec<(String, Limit)>,
+    buckets: Mutex<HashMap<(usize, String), Bucket>>,
+    max_keys: usize,
+}
+
+impl RateLimitMiddleware {
+    /// Allows `requests` per `period` for every client on every route.
+    pub fn new(requests: u32, period: Duration) -> RateLimitMiddleware {
+        RateLimitMiddleware {
+            default: Some(Limit::new(requests, period)),
+            ..RateLimitMiddleware::routes_only()
+        }
+    }
+
+    /// Limits only the routes declared with [`RateLimitMiddleware::route`].
+    pub fn routes_only() -> RateLimitMiddleware {
+        RateLimitMiddleware {
+            key: KeySource::Peer,
+            default: None,
+            routes: Vec::new(),
+            buckets: Mutex::new(HashMap::new()),
+            max_keys: 10_000,
+        }
+    }
+
+    /// Gives paths starting with `path` their own limit.
+    pub fn route(mut self, path: &str, requests: u32, period: Duration) -> RateLimitMiddleware {
+        self.routes.push((path.to_owned(), Limit::new(requests, period)));
+        self
+    }
+
+    /// Keys clients by their authenticated identity, falling back to the peer address.
+    pub fn key_by_identity(mut self, authenticator: Arc<dyn Authenticator>) -> RateLimitMiddleware {
+        self.key = KeySource::Identity(authenticator);
+        self
+    }
+
+    /// Keys clients with a custom function of the raw request. Returning `None` falls back to the
+    /// peer address.
+    pub fn key_by(mut self, key: impl Fn(&str) -> Option<String> + Send + Sync + 'static) -> RateLimitMiddleware {
+        self.key = KeySource::Custom(Box::new(key));
+        self
+    }
+
+    /// Caps the number of tracked buckets.
+    pub fn max_keys(mut self, max_keys: usize) -> RateLimitMiddleware {
+        self.max_keys = max_keys.max(1);
+        self
+    }
+
+    fn client_key(&self, request: &str) -> String {
+        let key = match &self.key {
+            KeySource::Peer => None,
+            KeySource::Identity(authenticator) => authenticator
+                .authenticate(request)
+                .and_then(|identity| identity.subject)
+                .map(|subject| format!("id:{}", subject)),
+            KeySource::Custom(f) => f(request),
+        };
+        key.or_else(|| context::peer_addr().map(|addr| addr.ip().to_string()))
+            .unwrap_or_default()
+    }
+
+    fn evict(&self, buckets: &mut HashMap<(usize, String), Bucket>, now: Instant) {
+        // Buckets that have refilled completely carry no state worth keeping
+        buckets.retain(|(route, _), bucket| {
+            let limit = self.limit_at(*route);
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * limit.refill_per_sec() < limit.capacity as f64
+        });
+        while buckets.len() >= self.max_keys {
+            let oldest = buckets
+                .iter()
+                .min_by_key(|(_, bucket)| bucket.updated)
+                .map(|(key, _)| key.clone());
+            match oldest {
+                Some(key) => buckets.remove(&key),
+                None => break,
+            };
+        }
+    }
+
+    fn limit_at(&self, route: usize) -> Limit {
+        match self.routes.get(route) {
+            Some((_, limit)) => *limit,
+            None => self.default.unwrap(),
+        }
+    }
+
+    /// Takes one token from the client's bucket, returning whether the request may proceed.
+    fn take(&self, route: usize, key: String) -> (bool, Status) {
+        let limit = self.limit_at(route);
+        let rate = limit.refill_per_sec();
+        let now = Instant::now();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(&(route, key.clone())) && buckets.len() >= self.max_keys {
+            self.evict(&mut buckets, now);
+        }
+        let bucket = buckets.entry((route, key)).or_insert(Bucket {
+            tokens: limit.capacity as f64,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(limit.capacity as f64);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let status = Status {
+            limit: limit.capacity,
+            remaining: bucket.tokens.floor() as u32,
+            reset: ((limit.capacity as f64 - bucket.tokens) / rate).ceil() as u64,
+        };
+        let retry_after = ((1.0 - bucket.tokens) / rate).ceil().max(1.0) as u64;
+        (allowed, Status { reset: if allowed { status.reset } else { retry_after }, ..status })
+    }
+}
+
+fn with_status(response: &str, status: &Status) -> String {
+    let response = add_header(response, "RateLimit-Reset", &status.reset.to_string());
+    let response = add_header(&response, "RateLimit-Remaining", &status.remaining.to_string());
+    add_header(&response, "RateLimit-Limit", &status.limit.to_string())
+}
+
+impl Middleware for RateLimitMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (_, path, _) = request_target(request)?;
+        let route = match self.routes.iter().position(|(prefix, _)| path.starts_with(prefix.as_str())) {
+            Some(route) => route,
+            None if self.default.is_some() => self.routes.len(),
+            None => return None,
+        };
+
+        let (allowed, status) = self.take(route, self.client_key(request));
+        if allowed {
+            context::insert(status);
+            return None;
+        }
+        let response = Problem::new(429)
+            .detail("Too many requests.")
+            .header("Retry-After", &status.reset.to_string())
+            .response();
+        Some(with_status(&response, &status))
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let status = context::remove::<Status>()?;
+        Some(with_status(response, &status))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Identity;
+    use std::net::SocketAddr;
+
+    /// Identifies clients by an `X-User` header.
+    struct UserHeader;
+
+    impl Authenticator for UserHeader {
+        fn authenticate(&self, request: &str) -> Option<Identity> {
+            crate::header(request, "X-User").map(Identity::new)
+        }
+    }
+
+    fn peer(last: u8) -> Option<SocketAddr> {
+        Some(SocketAddr::from(([10, 0, 0, last], 4000)))
+    }
+
+    fn allowed(limiter: &RateLimitMiddleware, peer: Option<SocketAddr>, request: &str) -> bool {
+        context::scope(peer, || limiter.before(request).is_none())
+    }
+
+    #[test]
+    fn rejects_with_retry_after_and_refills() {
+        let limiter = RateLimitMiddleware::new(2, Duration::from_millis(100));
+        let request = "GET / HTTP/1.1\r\n\r\n";
+        assert!(allowed(&limiter, peer(1), request));
+        assert!(allowed(&limiter, peer(1), request));
+        let rejected = context::scope(peer(1), || limiter.before(request)).unwrap();
+        assert!(rejected.starts_with("HTTP/1.1 429 TOO MANY REQUESTS\r\n"));
+        assert!(rejected.contains("\r\nRetry-After: 1\r\n"));
+        assert!(rejected.contains("\r\nRateLimit-Limit: 2\r\n"));
+        assert!(rejected.contains("\r\nRateLimit-Remaining: 0\r\n"));
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(allowed(&limiter, peer(1), request));
+        assert!(!allowed(&limiter, peer(1), request));
+    }
+
+    #[test]
+    fn routes_have_their_own_buckets() {
+        let limiter = RateLimitMiddleware::routes_only().route("/login", 1, Duration::from_secs(60));
+        assert!(allowed(&limiter, peer(1), "POST /login HTTP/1.1\r\n\r\n"));
+        assert!(!allowed(&limiter, peer(1), "POST /login HTTP/1.1\r\n\r\n"));
+        for _ in 0..3 {
+            assert!(allowed(&limiter, peer(1), "GET /items HTTP/1.1\r\n\r\n"));
+        }
+
+        let limiter = RateLimitMiddleware::new(1, Duration::from_secs(60)).route("/api", 2, Duration::from_secs(60));
+        assert!(allowed(&limiter, peer(1), "GET / HTTP/1.1\r\n\r\n"));
+        assert!(!allowed(&limiter, peer(1), "GET /other HTTP/1.1\r\n\r\n"));
+        assert!(allowed(&limiter, peer(1), "GET /api/a HTTP/1.1\r\n\r\n"));
+        assert!(allowed(&limiter, peer(1), "GET /api/b HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn keys_by_identity_or_peer() {
+        let by_peer = RateLimitMiddleware::new(1, Duration::from_secs(60));
+        assert!(allowed(&by_peer, peer(1), "GET / HTTP/1.1\r\nX-User: ann\r\n\r\n"));
+        assert!(!allowed(&by_peer, peer(1), "GET / HTTP/1.1\r\nX-User: bob\r\n\r\n"));
+        assert!(allowed(&by_peer, peer(2), "GET / HTTP/1.1\r\n\r\n"));
+
+        let by_identity = RateLimitMiddleware::new(1, Duration::from_secs(60)).key_by_identity(Arc::new(UserHeader));
+        assert!(allowed(&by_identity, peer(1), "GET / HTTP/1.1\r\nX-User: ann\r\n\r\n"));
+        assert!(allowed(&by_identity, peer(1), "GET / HTTP/1.1\r\nX-User: bob\r\n\r\n"));
+        assert!(!allowed(&by_identity, peer(2), "GET / HTTP/1.1\r\nX-User: ann\r\n\r\n"));
+        // Anonymous requests fall back to the peer address
+        assert!(allowed(&by_identity, peer(1), "GET / HTTP/1.1\r\n\r\n"));
+        assert!(!allowed(&by_identity, peer(1), "GET / HTTP/1.1\r\n\r\n"));
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used_buckets() {
+        let limiter = RateLimitMiddleware::new(1, Duration::from_secs(60)).max_keys(2);
+        let request = "GET / HTTP/1.1\r\n\r\n";
+        assert!(allowed(&limiter, peer(1), request));
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(allowed(&limiter, peer(2), request));
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(allowed(&limiter, peer(3), request));
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 2);
+        // Peer 1's bucket was dropped, so it starts over; peer 3 is still limited
+        assert!(allowed(&limiter, peer(1), request));
+        assert!(!allowed(&limiter, peer(3), request));
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one request")]
+    fn zero_capacity_is_rejected() {
+        RateLimitMiddleware::routes_only().route("/", 0, Duration::from_secs(1));
+    }
+
+    #[test]
+    #[should_panic(expected = "must not be zero")]
+    fn zero_periods_are_rejected() {
+        RateLimitMiddleware::new(10, Duration::ZERO);
+    }
+}
@@ -0,0 +1,423 @@
+use serde_json::{Map, Value};
+
+use crate::patch;
+use crate::{JsonFile, Problem};
+
+fn json_response(status: &str, body: &Value) -> String {
+    format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\n\r\n{}",
+        status,
+        serde_json::to_string(body).unwrap_or_default()
+    )
+}
+
+fn json_error(status: u16, message: &str) -> String {
+    Problem::new(status).detail(message).response()
+}
+
+fn method_not_allowed(allow: &str) -> String {
+    Problem::new(405).header("Allow", allow).response()
+}
+
+/// Renders an id the way it appears in URLs, so `7` and `"7"` name the same item.
+fn id_string(id: &Value) -> Option<String> {
+    match id {
+        Value::String(id) => Some(id.clone()),
+        Value::Number(id) => Some(id.to_string()),
+        _ => None,
+    }
+}
+
+/// Which part of a resource a request path addresses.
+enum Target<'a> {
+    Collection,
+    Item(&'a str),
+}
+
+/// A REST collection stored in a JSON file.
+///
+/// The file holds either an array of objects with an `"id"` field, or an object keyed by id (the
+/// layout `delete!` works with). A missing file is an empty collection and is created on the
+/// first write. Writes go through `JsonFile`, so concurrent requests cannot lose each other's
+/// changes. Ids of new items are numeric and increasing when every existing id is a number,
+/// and random strings otherwise; a client may also choose its own by sending an `"id"`.
+///
+/// | Request               | Success                         | Failure                           |
+/// |-----------------------|---------------------------------|-----------------------------------|
+/// | `GET /items`          | `200` with an array of items    | `400`                             |
+/// | `GET /items/{id}`     | `200` with the item             | `404`                             |
+/// | `POST /items`         | `201` with the item, `Location` | `400`, `409`, `422`               |
+/// | `PUT /items/{id}`     | `200` with the replaced item    | `400`, `404`, `422`               |
+/// | `PATCH /items/{id}`   | `200` with the patched item     | `400`, `404`, `409`, `415`, `422` |
+/// | `DELETE /items/{id}`  | `204`                           | `404`                             |
+///
+/// Errors are `Problem` responses. See `json_resource!` for mounting a
+/// resource on a `Handler`.
+pub struct JsonResource {
+    path: String,
+    file: JsonFile,
+}
+
+impl JsonResource {
+    pub fn new(path: &str, file: &str) -> JsonResource {
+        JsonResource {
+            path: path.trim_end_matches('/').to_owned(),
+            file: JsonFile::new(file),
+        }
+    }
+
+    /// Uses `file` for storage, e.g. to enable advisory locking or backups.
+    pub fn storage(mut self, file: JsonFile) -> JsonResource {
+        self.file = file;
+        self
+    }
+
+    fn target<'a>(&self, path: &'a str) -> Option<Target<'a>> {
+        let rest = path.strip_prefix(self.path.as_str())?;
+        match rest.trim_end_matches('/') {
+            "" => {
+                crate::__record_route(&self.path);
+                Some(Target::Collection)
+            }
+            rest => {
+                let id = rest.strip_prefix('/')?;
+                if id.contains('/') {
+                    return None;
+                }
+                crate::__record_route(&format!("{}/{{id}}", self.path));
+                Some(Target::Item(id))
+            }
+        }
+    }
+
+    fn load(&self) -> Result<Value, String> {
+        match self.file.read() {
+            Ok(Value::Null) => Ok(Value::Array(Vec::new())),
+            Ok(data) => Ok(data),
+            Err(e) => Err(self.storage_error(e)),
+        }
+    }
+
+    /// Applies `f` to the collection under the file's lock, writing it back if `f` succeeds.
+    fn modify<T>(&self, f: impl FnOnce(&mut Value) -> Result<T, String>) -> Result<T, String> {
+        let result = self.file.update(|data| {
+            if data.is_null() {
+                *data = Value::Array(Vec::new());
+            }
+            f(data)
+        });
+        result.map_err(|e| self.storage_error(e))?
+    }
+
+    fn storage_error(&self, e: std::io::Error) -> String {
+        error!("Failed to access {}: {}", self.file.path().display(), e);
+        if e.kind() == std::io::ErrorKind::InvalidData {
+            json_error(500, "the data file is not valid JSON")
+        } else {
+            json_error(500, "the data file could not be accessed")
+        }
+    }
+
+    /// Returns every item, with object-keyed items given their key as `"id"`.
+    fn items(data: &Value) -> Vec<Value> {
+        match data {
+            Value::Array(items) => items.clone(),
+            Value::Object(items) => items
+                .iter()
+                .map(|(id, item)| {
+                    let mut item = item.clone();
+                    if let Value::Object(fields) = &mut item {
+                        fields.insert("id".to_owned(), Value::String(id.clone()));
+                    }
+                    item
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn find<'d>(data: &'d mut Value, id: &str) -> Option<&'d mut Value> {
+        match data {
+            Value::Array(items) => items
+                .iter_mut()
+                .find(|item| item.get("id").and_then(id_string).as_deref() == Some(id)),
+            Value::Object(items) => items.get_mut(id),
+            _ => None,
+        }
+    }
+
+    fn next_id(data: &Value) -> Value {
+        let ids: Vec<String> = match data {
+            Value::Array(items) => items.iter().filter_map(|item| item.get("id").and_then(id_string)).collect(),
+            Value::Object(items) => items.keys().cloned().collect(),
+            _ => Vec::new(),
+        };
+        let numeric: Option<Vec<u64>> = ids.iter().map(|id| id.parse().ok()).collect();
+        match (numeric, data) {
+            (Some(ids), Value::Array(_)) => Value::from(ids.into_iter().max().map_or(1, |max| max + 1)),
+            (Some(ids), _) => Value::String(ids.into_iter().max().map_or(1, |max| max + 1).to_string()),
+            (None, _) => Value::String(crate::random_token(9)),
+        }
+    }
+
+    fn parse_item(body: &str) -> Result<Map<String, Value>, String> {
+        match serde_json::from_str(body) {
+            Ok(Value::Object(item)) => Ok(item),
+            Ok(_) => Err(json_error(422, "the request body must be a JSON object")),
+            Err(e) => Err(json_error(400, &format!("invalid JSON: {}", e))),
+        }
+    }
+
+    /// Stores `item` under `id`, in whichever layout the file uses.
+    fn put_item(data: &mut Value, id: &str, mut item: Map<String, Value>) -> Value {
+        match data {
+            Value::Object(items) => {
+                item.remove("id");
+                items.insert(id.to_owned(), Value::Object(item.clone()));
+                item.insert("id".to_owned(), Value::String(id.to_owned()));
+                Value::Object(item)
+            }
+            _ => {
+                let item = Value::Object(item);
+                match Self::find(data, id) {
+                    Some(existing) => *existing = item.clone(),
+                    None => {
+                        if let Value::Array(items) = data {
+                            items.push(item.clone());
+                        }
+                    }
+                }
+                item
+            }
+        }
+    }
+
+    /// Handles `GET` for the collection and for single items. The collection can be filtered,
+    /// sorted and paginated with the parameters described on `CollectionQuery`.
+    pub fn get(&self, path: &str, query: Option<&str>) -> Option<String> {
+        let target = self.target(path)?;
+        let data = match self.load() {
+            Ok(data) => data,
+            Err(response) => return Some(response),
+        };
+        Some(match target {
+            Target::Collection => crate::json_collection_response(&self.path, query, Self::items(&data)),
+            Target::Item(id) => {
+                let found = Self::items(&data)
+                    .into_iter()
+                    .find(|item| item.get("id").and_then(id_string).as_deref() == Some(id));
+                match found {
+                    Some(item) => json_response("200 OK", &item),
+                    None => json_error(404, &format!("no item with id '{}'", id)),
+                }
+            }
+        })
+    }
+
+    /// Handles `POST` to the collection, creating an item.
+    pub fn post(&self, path: &str, _query: Option<&str>, body: &str) -> Option<String> {
+        if let Target::Item(_) = self.target(path)? {
+            return Some(method_not_allowed("GET, PUT, PATCH, DELETE"));
+        }
+        let mut item = match Self::parse_item(body) {
+            Ok(item) => item,
+            Err(response) => return Some(response),
+        };
+        let created = self.modify(|data| {
+            let id = match item.get("id") {
+                Some(id) => match id_string(id) {
+                    Some(id) if Self::find(data, &id).is_some() => {
+                        return Err(json_error(409, &format!("an item with id '{}' already exists", id)));
+                    }
+                    Some(id) => id,
+                    None => return Err(json_error(422, "\"id\" must be a string or a number")),
+                },
+                None => {
+                    let id = Self::next_id(data);
+                    item.insert("id".to_owned(), id.clone());
+                    id_string(&id).unwrap_or_default()
+                }
+            };
+            Ok((Self::put_item(data, &id, item), id))
+        });
+        let (item, id) = match created {
+            Ok(created) => created,
+            Err(response) => return Some(response),
+        };
+        Some(crate::add_header(
+            &json_response("201 CREATED", &item),
+            "Location",
+            &format!("{}/{}", self.path, id),
+        ))
+    }
+
+    /// Handles `PUT` to an item, replacing it.
+    pub fn put(&self, path: &str, body: &str) -> Option<String> {
+        self.update(path, |_, id| {
+            let mut item = Self::parse_item(body)?;
+            item.insert("id".to_owned(), id);
+            Ok(item)
+        })
+    }
+
+    /// Handles `PATCH` to an item, applying the body as a JSON Patch or a JSON Merge Patch
+    /// depending on `content_type`.
+    pub fn patch(&self, path: &str, content_type: Option<&str>, body: &str) -> Option<String> {
+        self.update(path, |existing, id| {
+            let mut patched = existing;
+            if let Err(e) = patch::apply(&mut patched, content_type, body) {
+                return Err(Problem::from(e).response());
+            }
+            let mut patched = match patched {
+                Value::Object(patched) => patched,
+                _ => return Err(json_error(422, "the patched item must be a JSON object")),
+            };
+            // The id comes from the URL and cannot be patched away
+            patched.insert("id".to_owned(), id);
+            Ok(patched)
+        })
+    }
+
+    /// Replaces an item with what `apply` makes of the stored item and its id.
+    fn update(
+        &self,
+        path: &str,
+        apply: impl FnOnce(Value, Value) -> Result<Map<String, Value>, String>,
+    ) -> Option<String> {
+        let id = match self.target(path)? {
+            Target::Item(id) => id,
+            Target::Collection => return Some(method_not_allowed("GET, POST")),
+        };
+        let updated = self.modify(|data| {
+            let existing = match Self::items(data)
+                .into_iter()
+                .find(|item| item.get("id").and_then(id_string).as_deref() == Some(id))
+            {
+                Some(existing) => existing,
+                None => return Err(json_error(404, &format!("no item with id '{}'", id))),
+            };
+            // Keep the stored id's type, so numeric ids stay numbers
+            let stored_id = existing.get("id").cloned().unwrap_or_else(|| Value::String(id.to_owned()));
+            let item = apply(existing, stored_id)?;
+            Ok(Self::put_item(data, id, item))
+        });
+        let item = match updated {
+            Ok(item) => item,
+            Err(response) => return Some(response),
+        };
+        Some(json_response("200 OK", &item))
+    }
+
+    /// Handles `DELETE` of an item.
+    pub fn delete(&self, path: &str, _query: Option<&str>) -> Option<String> {
+        let id = match self.target(path)? {
+            Target::Item(id) => id,
+            Target::Collection => return Some(method_not_allowed("GET, POST")),
+        };
+        let removed = self.modify(|data| {
+            let removed = match data {
+                Value::Array(items) => {
+                    let before = items.len();
+                    items.retain(|item| item.get("id").and_then(id_string).as_deref() != Some(id));
+                    items.len() != before
+                }
+                Value::Object(items) => items.remove(id).is_some(),
+                _ => false,
+            };
+            if removed {
+                Ok(())
+            } else {
+                Err(json_error(404, &format!("no item with id '{}'", id)))
+            }
+        });
+        if let Err(response) = removed {
+            return Some(response);
+        }
+        Some("HTTP/1.1 204 NO CONTENT\r\n\r\n".to_owned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn resource(name: &str, data: Option<Value>) -> (JsonResource, std::path::PathBuf) {
+        let path = std::env::temp_dir().join(format!("parfait-resource-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        if let Some(data) = data {
+            std::fs::write(&path, data.to_string()).unwrap();
+        }
+        (JsonResource::new("/items", path.to_str().unwrap()), path)
+    }
+
+    fn status(response: &str) -> &str {
+        response.split("\r\n").next().unwrap()
+    }
+
+    fn body(response: &str) -> Value {
+        serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn creates_items_with_ids_and_locations() {
+        let (items, path) = resource("create", None);
+        let created = items.post("/items", None, r#"{"name": "pen"}"#).unwrap();
+        assert_eq!(status(&created), "HTTP/1.1 201 CREATED");
+        assert!(created.contains("\r\nLocation: /items/1\r\n"));
+        assert_eq!(body(&created), json!({ "name": "pen", "id": 1 }));
+
+        let second = items.post("/items/", None, r#"{"name": "ink"}"#).unwrap();
+        assert!(second.contains("\r\nLocation: /items/2\r\n"));
+        let conflict = items.post("/items", None, r#"{"id": 2, "name": "cap"}"#).unwrap();
+        assert_eq!(status(&conflict), "HTTP/1.1 409 CONFLICT");
+        assert_eq!(status(&items.post("/items", None, "[1]").unwrap()), "HTTP/1.1 422 UNPROCESSABLE ENTITY");
+        assert_eq!(status(&items.post("/items", None, "{").unwrap()), "HTTP/1.1 400 BAD REQUEST");
+
+        assert_eq!(body(&items.get("/items", None).unwrap()).as_array().unwrap().len(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reads_replaces_and_deletes_items() {
+        let (items, path) = resource("crud", Some(json!([{ "id": 7, "name": "pen" }])));
+        assert_eq!(body(&items.get("/items/7", None).unwrap())["name"], "pen");
+        assert_eq!(status(&items.get("/items/8", None).unwrap()), "HTTP/1.1 404 NOT FOUND");
+
+        let replaced = items.put("/items/7", r#"{"name": "ink", "id": 99}"#).unwrap();
+        assert_eq!(body(&replaced), json!({ "name": "ink", "id": 7 }));
+        assert_eq!(status(&items.put("/items/8", r#"{"name": "cap"}"#).unwrap()), "HTTP/1.1 404 NOT FOUND");
+
+        assert_eq!(items.delete("/items/7", None).unwrap(), "HTTP/1.1 204 NO CONTENT\r\n\r\n");
+        assert_eq!(status(&items.delete("/items/7", None).unwrap()), "HTTP/1.1 404 NOT FOUND");
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), "[]");
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn supports_object_keyed_files() {
+        let (items, path) = resource("keyed", Some(json!({ "a": { "name": "pen" } })));
+        assert_eq!(body(&items.get("/items/a", None).unwrap()), json!({ "name": "pen", "id": "a" }));
+        let created = items.post("/items", None, r#"{"id": "b", "name": "ink"}"#).unwrap();
+        assert!(created.contains("\r\nLocation: /items/b\r\n"));
+        let stored: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(stored, json!({ "a": { "name": "pen" }, "b": { "name": "ink" } }));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rejects_methods_a_path_does_not_support() {
+        let (items, _) = resource("methods", None);
+        let on_item = items.post("/items/1", None, "{}").unwrap();
+        assert_eq!(status(&on_item), "HTTP/1.1 405 METHOD NOT ALLOWED");
+        assert!(on_item.contains("\r\nAllow: GET, PUT, PATCH, DELETE\r\n"));
+        for response in [items.put("/items", "{}"), items.delete("/items", None)] {
+            let response = response.unwrap();
+            assert_eq!(status(&response), "HTTP/1.1 405 METHOD NOT ALLOWED");
+            assert!(response.contains("\r\nAllow: GET, POST\r\n"));
+        }
+        assert_eq!(items.get("/other", None), None);
+        assert_eq!(items.get("/items/1/parts", None), None);
+    }
+}
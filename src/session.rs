@@ -0,0 +1,464 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{context, random_token, Cookie, CookieJar, Middleware, SameSite};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// The stored state of one session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SessionRecord {
+    pub data: Map<String, Value>,
+    /// Creation time in seconds since the Unix epoch.
+    pub created_at: u64,
+    /// Time of the last request that used the session, in seconds since the Unix epoch.
+    pub last_seen: u64,
+}
+
+impl SessionRecord {
+    fn is_expired(&self, idle_timeout: Duration, absolute_timeout: Duration) -> bool {
+        let now = now();
+        now >= self.last_seen + idle_timeout.as_secs() || now >= self.created_at + absolute_timeout.as_secs()
+    }
+}
+
+/// Storage backend for server-side sessions.
+pub trait SessionStore: Send + Sync {
+    fn load(&self, id: &str) -> Option<SessionRecord>;
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()>;
+    fn destroy(&self, id: &str) -> io::Result<()>;
+
+    /// Removes sessions that have outlived either timeout.
+    fn sweep(&self, idle_timeout: Duration, absolute_timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keeps sessions in process memory; they are lost on restart.
+#[derive(Default)]
+pub struct MemoryStore {
+    sessions: Mutex<HashMap<String, SessionRecord>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl SessionStore for MemoryStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        self.sessions.lock().unwrap().get(id).cloned()
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        self.sessions.lock().unwrap().insert(id.to_owned(), record.clone());
+        Ok(())
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        self.sessions.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    fn sweep(&self, idle_timeout: Duration, absolute_timeout: Duration) -> io::Result<()> {
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, record| !record.is_expired(idle_timeout, absolute_timeout));
+        Ok(())
+    }
+}
+
+/// Keeps each session as a JSON file in a directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: &str) -> io::Result<FileStore> {
+        std::fs::create_dir_all(dir)?;
+        Ok(FileStore { dir: PathBuf::from(dir) })
+    }
+
+    fn path(&self, id: &str) -> Option<PathBuf> {
+        // Session IDs come from the client, so never let one escape the directory
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return None;
+        }
+        Some(self.dir.join(format!("{}.json", id)))
+    }
+}
+
+impl SessionStore for FileStore {
+    fn load(&self, id: &str) -> Option<SessionRecord> {
+        let content = std::fs::read_to_string(self.path(id)?).ok()?;
+        serde_json::from_str(&content).ok()
+    }
+
+    fn save(&self, id: &str, record: &SessionRecord) -> io::Result<()> {
+        let path = self
+            .path(id)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid session id"))?;
+        std::fs::write(path, serde_json::to_string(record)?)
+    }
+
+    fn destroy(&self, id: &str) -> io::Result<()> {
+        match self.path(id) {
+            Some(path) => match std::fs::remove_file(path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn sweep(&self, idle_timeout: Duration, absolute_timeout: Duration) -> io::Result<()> {
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("json") {
+                continue;
+            }
+            let expired = std::fs::read_to_string(&path)
+                .ok()
+                .and_then(|content| serde_json::from_str::<SessionRecord>(&content).ok())
+                .is_none_or(|record| record.is_expired(idle_timeout, absolute_timeout));
+            if expired {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The session of the current request.
+///
+/// Handlers reach it through [`with_session`] while [`SessionMiddleware`] is installed.
+#[derive(Debug)]
+pub struct Session {
+    id: Option<String>,
+    record: SessionRecord,
+    changed: bool,
+    regenerate: bool,
+    destroyed: bool,
+}
+
+impl Session {
+    fn new() -> Session {
+        let now = now();
+        Session {
+            id: None,
+            record: SessionRecord {
+                data: Map::new(),
+                created_at: now,
+                last_seen: now,
+            },
+            changed: false,
+            regenerate: false,
+            destroyed: false,
+        }
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        serde_json::from_value(self.record.data.get(key)?.clone()).ok()
+    }
+
+    pub fn insert<T: Serialize>(&mut self, key: &str, value: T) {
+        if let Ok(value) = serde_json::to_value(value) {
+            self.record.data.insert(key.to_owned(), value);
+            self.changed = true;
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.changed = true;
+        self.record.data.remove(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.record.data.clear();
+        self.changed = true;
+    }
+
+    /// Issues a new session ID for the same data. Call this after login to prevent session fixation.
+    pub fn regenerate(&mut self) {
+        self.regenerate = true;
+        self.changed = true;
+    }
+
+    /// Deletes the session from the store and the client.
+    pub fn destroy(&mut self) {
+        self.record.data.clear();
+        self.destroyed = true;
+    }
+}
+
+/// Calls `f` with the current request's session, if [`SessionMiddleware`] is installed.
+pub fn with_session<R>(f: impl FnOnce(&mut Session) -> R) -> Option<R> {
+    context::with(f)
+}
+
+/// Middleware that loads the session named by a cookie before the handler runs and saves it after.
+pub struct SessionMiddleware {
+    store: Arc<dyn SessionStore>,
+    cookie_name: String,
+    secure: bool,
+    idle_timeout: Duration,
+    absolute_timeout: Duration,
+}
+
+impl SessionMiddleware {
+    pub fn new(store: Arc<dyn SessionStore>) -> SessionMiddleware {
+        SessionMiddleware {
+            store,
+            cookie_name: "parfait_session".to_owned(),
+            secure: false,
+            idle_timeout: Duration::from_secs(30 * 60),
+            absolute_timeout: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+
+    pub fn cookie_name(mut self, name: &str) -> SessionMiddleware {
+        self.cookie_name = name.to_owned();
+        self
+    }
+
+    /// Marks the session cookie `Secure`; enable this when served over HTTPS.
+    pub fn secure(mut self, secure: bool) -> SessionMiddleware {
+        self.secure = secure;
+        self
+    }
+
+    /// Ends sessions that have not been used for this long.
+    pub fn idle_timeout(mut self, timeout: Duration) -> SessionMiddleware {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Ends sessions this long after they were created, however active they are.
+    pub fn absolute_timeout(mut self, timeout: Duration) -> SessionMiddleware {
+        self.absolute_timeout = timeout;
+        self
+    }
+
+    /// Spawns a task that removes expired sessions from the store every `every`.
+    pub fn spawn_sweeper(&self, every: Duration) -> tokio::task::JoinHandle<()> {
+        let store = self.store.clone();
+        let (idle, absolute) = (self.idle_timeout, self.absolute_timeout);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            loop {
+                interval.tick().await;
+                let store = store.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || store.sweep(idle, absolute)).await {
+                    error!("Failed to sweep sessions: {}", e);
+                }
+            }
+        })
+    }
+
+    fn cookie(&self, id: &str) -> Cookie {
+        Cookie::new(&self.cookie_name, id)
+            .path("/")
+            .http_only(true)
+            .secure(self.secure)
+            .same_site(SameSite::Lax)
+    }
+}
+
+impl Middleware for SessionMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let mut session = Session::new();
+        if let Some(id) = CookieJar::from_request(request).get(&self.cookie_name) {
+            match self.store.load(id) {
+                Some(record) if !record.is_expired(self.idle_timeout, self.absolute_timeout) => {
+                    session.id = Some(id.to_owned());
+                    session.record = record;
+                }
+                Some(_) => {
+                    let _ = self.store.destroy(id);
+                }
+                None => {}
+            }
+        }
+        context::insert(session);
+        None
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        let mut session = context::remove::<Session>()?;
+        let mut jar = CookieJar::default();
+
+        if session.destroyed {
+            if let Some(id) = &session.id {
+                let _ = self.store.destroy(id);
+                jar.remove(&self.cookie_name, "/");
+            }
+            return Some(jar.apply(response));
+        }
+
+        // Don't create sessions for visitors who never stored anything
+        if session.id.is_none() && !session.changed {
+            return None;
+        }
+
+        let id = match session.id.take() {
+            Some(old) if session.regenerate => {
+                let _ = self.store.destroy(&old);
+                None
+            }
+            id => id,
+        };
+        let id = match id {
+            Some(id) => id,
+            None => {
+                let id = random_token(32);
+                jar.add(self.cookie(&id));
+                id
+            }
+        };
+
+        session.record.last_seen = now();
+        if let Err(e) = self.store.save(&id, &session.record) {
+            error!("Failed to save session: {}", e);
+        }
+        Some(jar.apply(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OK: &str = "HTTP/1.1 200 OK\r\n\r\n";
+
+    fn record(age: u64, idle: u64) -> SessionRecord {
+        let mut data = Map::new();
+        data.insert("user".to_owned(), Value::from("ann"));
+        SessionRecord {
+            data,
+            created_at: now() - age,
+            last_seen: now() - idle,
+        }
+    }
+
+    /// Runs one request through `sessions`, with `handler` standing in for the route.
+    fn request(sessions: &SessionMiddleware, id: Option<&str>, handler: impl FnOnce()) -> String {
+        let request = match id {
+            Some(id) => format!("GET / HTTP/1.1\r\nCookie: parfait_session={}\r\n\r\n", id),
+            None => "GET / HTTP/1.1\r\n\r\n".to_owned(),
+        };
+        context::scope(None, || {
+            assert_eq!(sessions.before(&request), None);
+            handler();
+            sessions.after(OK).unwrap_or_else(|| OK.to_owned())
+        })
+    }
+
+    fn issued_id(response: &str) -> Option<String> {
+        let start = response.find("Set-Cookie: parfait_session=")? + "Set-Cookie: parfait_session=".len();
+        response[start..].split(';').next().map(str::to_owned)
+    }
+
+    #[test]
+    fn sessions_persist_between_requests() {
+        let store = Arc::new(MemoryStore::new());
+        let sessions = SessionMiddleware::new(store.clone());
+        assert_eq!(request(&sessions, None, || {}), OK);
+
+        let response = request(&sessions, None, || {
+            with_session(|session| session.insert("user", "ann"));
+        });
+        let id = issued_id(&response).unwrap();
+        assert!(response.contains("HttpOnly"));
+
+        let response = request(&sessions, Some(&id), || {
+            let user = with_session(|session| session.get::<String>("user")).flatten();
+            assert_eq!(user.as_deref(), Some("ann"));
+        });
+        assert_eq!(issued_id(&response), None);
+    }
+
+    #[test]
+    fn expired_sessions_are_dropped() {
+        let store = Arc::new(MemoryStore::new());
+        store.save("idle", &record(60, 31 * 60)).unwrap();
+        store.save("old", &record(25 * 60 * 60, 0)).unwrap();
+        store.save("fresh", &record(60, 0)).unwrap();
+        let sessions = SessionMiddleware::new(store.clone());
+
+        for id in ["idle", "old"] {
+            request(&sessions, Some(id), || {
+                assert_eq!(with_session(|session| session.get::<String>("user")), Some(None));
+            });
+            assert!(store.load(id).is_none());
+        }
+
+        store.save("idle", &record(60, 31 * 60)).unwrap();
+        store.sweep(Duration::from_secs(30 * 60), Duration::from_secs(24 * 60 * 60)).unwrap();
+        assert!(store.load("idle").is_none());
+        assert!(store.load("fresh").is_some());
+    }
+
+    #[test]
+    fn regenerate_issues_a_new_id_and_drops_the_old_one() {
+        let store = Arc::new(MemoryStore::new());
+        store.save("before-login", &record(60, 0)).unwrap();
+        let sessions = SessionMiddleware::new(store.clone());
+
+        let response = request(&sessions, Some("before-login"), || {
+            with_session(Session::regenerate);
+        });
+        let id = issued_id(&response).unwrap();
+        assert_ne!(id, "before-login");
+        assert!(store.load("before-login").is_none());
+        assert_eq!(store.load(&id).unwrap().data["user"], "ann");
+    }
+
+    #[test]
+    fn destroy_removes_the_session_and_cookie() {
+        let store = Arc::new(MemoryStore::new());
+        store.save("abc", &record(60, 0)).unwrap();
+        let sessions = SessionMiddleware::new(store.clone());
+
+        let response = request(&sessions, Some("abc"), || {
+            with_session(Session::destroy);
+        });
+        assert!(response.contains("Set-Cookie: parfait_session=; "));
+        assert!(store.load("abc").is_none());
+    }
+
+    #[test]
+    fn file_store_rejects_ids_outside_its_directory() {
+        let dir = std::env::temp_dir().join(format!("parfait-sessions-{}", std::process::id()));
+        let store = FileStore::new(dir.to_str().unwrap()).unwrap();
+        std::fs::write(dir.with_extension("json"), "{}").unwrap();
+
+        for id in ["../evil", "..", "a/b", "a\\b", "", "x.json"] {
+            let error = store.save(id, &record(0, 0)).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+            assert!(store.load(id).is_none());
+        }
+        let escaped = format!("../{}", dir.file_name().unwrap().to_str().unwrap());
+        assert!(store.load(&escaped).is_none());
+        assert!(store.destroy(&escaped).is_ok());
+        assert!(dir.with_extension("json").exists());
+
+        store.save("ok-id_1", &record(0, 0)).unwrap();
+        assert_eq!(store.load("ok-id_1").unwrap().data["user"], "ann");
+        store.destroy("ok-id_1").unwrap();
+        assert!(store.load("ok-id_1").is_none());
+
+        std::fs::remove_file(dir.with_extension("json")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
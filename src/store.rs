@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use serde_json::Value;
+
+use crate::json_pointer::{self, PointerError};
+use crate::{JsonFile, Problem};
+
+/// A thread-safe in-memory map of JSON values that route macros can serve and modify.
+///
+/// Declare one as a `static` and pass it to `get!`, `post!`, `put!`, `delete!` or `patch!` as
+/// `ContentType::Store(&STORE)`. The part of the request path after the route's prefix is the
+/// key, so a store mounted at `/kv` keeps `/kv/greeting` under `greeting`:
+///
+/// ```text
+/// static STORE: JsonStore = JsonStore::new();
+///
+/// get!("/kv", read => ContentType::Store(&STORE), "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\r\n");
+/// put!("/kv", write => ContentType::Store(&STORE), "application/json");
+/// ```
+///
+/// Contents live as long as the process unless snapshotted to a JSON file with `save`,
+/// `spawn_snapshots` or `save_on_shutdown`, and restored with `load`.
+pub struct JsonStore {
+    entries: RwLock<BTreeMap<String, Value>>,
+    version: AtomicU64,
+}
+
+impl Default for JsonStore {
+    fn default() -> Self {
+        JsonStore::new()
+    }
+}
+
+impl JsonStore {
+    pub const fn new() -> JsonStore {
+        JsonStore {
+            entries: RwLock::new(BTreeMap::new()),
+            version: AtomicU64::new(0),
+        }
+    }
+
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, BTreeMap<String, Value>> {
+        self.entries.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, BTreeMap<String, Value>> {
+        self.version.fetch_add(1, Ordering::Relaxed);
+        self.entries.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.read().get(key).cloned()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.read().contains_key(key)
+    }
+
+    /// Stores `value` under `key`, returning the value it replaced.
+    pub fn insert(&self, key: &str, value: Value) -> Option<Value> {
+        self.write().insert(key.to_owned(), value)
+    }
+
+    pub fn remove(&self, key: &str) -> Option<Value> {
+        self.write().remove(key)
+    }
+
+    /// Runs `f` on the value under `key` (`None` if absent) while holding the write lock, so
+    /// read-modify-write sequences are not interleaved with other requests.
+    pub fn update<R>(&self, key: &str, f: impl FnOnce(&mut Option<Value>) -> R) -> R {
+        let mut entries = self.write();
+        let mut value = entries.remove(key);
+        let result = f(&mut value);
+        if let Some(value) = value {
+            entries.insert(key.to_owned(), value);
+        }
+        result
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.read().keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// The whole store as a JSON object.
+    pub fn to_value(&self) -> Value {
+        Value::Object(self.read().iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    /// Replaces the contents with a snapshot written by `save`. A missing file leaves the store
+    /// empty.
+    pub fn load(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let entries = match JsonFile::new(path).read()? {
+            Value::Null => BTreeMap::new(),
+            Value::Object(entries) => entries.into_iter().collect(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "a store snapshot must be a JSON object")),
+        };
+        *self.write() = entries;
+        Ok(())
+    }
+
+    /// Writes the contents to `path` as a JSON object, atomically.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        JsonFile::new(path).write(&self.to_value())
+    }
+
+    /// Spawns a task that saves the store to `path` every `every`, skipping unchanged stores.
+    pub fn spawn_snapshots(&'static self, path: impl Into<PathBuf>, every: Duration) -> tokio::task::JoinHandle<()> {
+        let path = path.into();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(every);
+            let mut saved = None;
+            loop {
+                interval.tick().await;
+                let version = self.version.load(Ordering::Relaxed);
+                if saved == Some(version) {
+                    continue;
+                }
+                let path = path.clone();
+                match tokio::task::spawn_blocking(move || self.save(path)).await {
+                    Ok(Ok(())) => saved = Some(version),
+                    Ok(Err(e)) => error!("Failed to snapshot store: {}", e),
+                    Err(_) => {}
+                }
+            }
+        })
+    }
+
+    /// Waits for Ctrl+C (or SIGTERM on Unix), then saves the store to `path`.
+    ///
+    /// Exiting is left to the caller, so several stores can each save before the process ends:
+    ///
+    /// ```text
+    /// tokio::select! {
+    ///     result = run("127.0.0.1", 8080, handler) => result,
+    ///     saved = async {
+    ///         tokio::try_join!(USERS.save_on_shutdown("users.json"), ITEMS.save_on_shutdown("items.json"))
+    ///     } => saved.map(drop),
+    /// }
+    /// ```
+    pub async fn save_on_shutdown(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        shutdown_signal().await;
+        info!("Saving store to {} before shutting down", path.as_ref().display());
+        self.save(&path).inspect_err(|e| error!("Failed to snapshot store: {}", e))
+    }
+}
+
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+/// The store key a request path addresses below a route's prefix.
+fn key_of<'p>(prefix: &str, path: &'p str) -> &'p str {
+    path.strip_prefix(prefix).unwrap_or("").trim_matches('/')
+}
+
+fn response(status: &str, content_type: &str, body: &Value) -> String {
+    format!("HTTP/1.1 {}\r\nContent-Type: {}\r\n\r\n{}", status, content_type, body)
+}
+
+fn not_found(key: &str) -> String {
+    Problem::new(404).detail(&format!("No entry '{}'.", key)).response()
+}
+
+fn unremovable(key: &str, reason: &str) -> String {
+    Problem::new(422).detail(&format!("Cannot remove '{}': {}.", key, reason)).response()
+}
+
+/// A request body as a JSON value, or as a string when it is not JSON.
+fn body_value(body: &str) -> Value {
+    serde_json::from_str(body).unwrap_or_else(|_| Value::String(body.to_owned()))
+}
+
+/// Reads the value under the request's key, or the whole store for the route's own path.
+#[doc(hidden)]
+pub fn __store_get(store: &JsonStore, prefix: &str, path: &str) -> Option<String> {
+    match key_of(prefix, path) {
+        "" => Some(store.to_value().to_string()),
+        key => store.get(key).map(|value| value.to_string()),
+    }
+}
+
+/// Creates an entry from a POST body, under the request's key or a generated one.
+#[doc(hidden)]
+pub fn __store_post(store: &JsonStore, prefix: &str, path: &str, body: &str, content_type: &str) -> String {
+    let value = body_value(body);
+    let key = match key_of(prefix, path) {
+        "" => crate::random_token(9),
+        key => key.to_owned(),
+    };
+    let created = store.update(&key, |entry| match entry {
+        Some(_) => false,
+        None => {
+            *entry = Some(value.clone());
+            true
+        }
+    });
+    if !created {
+        return Problem::new(409).detail(&format!("Entry '{}' already exists.", key)).response();
+    }
+    let location = format!("{}/{}", prefix.trim_end_matches('/'), key);
+    crate::add_header(&response("201 CREATED", content_type, &value), "Location", &location)
+}
+
+/// Creates or replaces the entry under the request's key with a PUT body.
+#[doc(hidden)]
+pub fn __store_put(store: &JsonStore, prefix: &str, path: &str, body: &str, content_type: &str) -> String {
+    let key = key_of(prefix, path);
+    if key.is_empty() {
+        return Problem::new(405).header("Allow", "GET, POST").response();
+    }
+    let value = body_value(body);
+    match store.insert(key, value.clone()) {
+        Some(_) => response("200 OK", content_type, &value),
+        None => response("201 CREATED", content_type, &value),
+    }
+}
+
+/// Removes the entry under the request's key, or the value a JSON Pointer `key` addresses.
+#[doc(hidden)]
+pub fn __store_delete(store: &JsonStore, prefix: &str, path: &str, key: Option<&str>) -> String {
+    let key = key.unwrap_or_else(|| key_of(prefix, path));
+    if !key.starts_with('/') {
+        return match store.remove(key) {
+            Some(_) => format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nElement '{}' removed successfully.", key),
+            None => not_found(key),
+        };
+    }
+
+    // A pointer's first token names the entry, the rest reaches into its value
+    let tokens = match json_pointer::parse(key) {
+        Ok(tokens) => tokens,
+        Err(PointerError::Invalid(reason) | PointerError::NotFound(reason)) => return unremovable(key, &reason),
+    };
+    let entry = tokens.first().cloned().unwrap_or_default();
+    let rest: String = tokens[1.min(tokens.len())..]
+        .iter()
+        .map(|token| format!("/{}", token.replace('~', "~0").replace('/', "~1")))
+        .collect();
+    let removed = store.update(&entry, |value| match value {
+        None => Err(PointerError::NotFound(format!("/{}", entry))),
+        Some(_) if rest.is_empty() => {
+            *value = None;
+            Ok(())
+        }
+        Some(value) => json_pointer::remove(value, &rest).map(drop),
+    });
+    match removed {
+        Ok(()) => format!("HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nElement '{}' removed successfully.", key),
+        Err(PointerError::NotFound(_)) => not_found(key),
+        Err(PointerError::Invalid(reason)) => unremovable(key, &reason),
+    }
+}
+
+/// Applies a patch request to the entry under the request's key.
+#[doc(hidden)]
+pub fn __store_patch(store: &JsonStore, prefix: &str, path: &str, content_type: Option<&str>, body: &str) -> String {
+    let key = key_of(prefix, path);
+    store.update(key, |value| match value {
+        Some(value) => crate::patch_response(crate::patch::apply(value, content_type, body).map(|()| value.clone())),
+        None => not_found(key),
+    })
+}
@@ -0,0 +1,94 @@
+//! Diagnostics that go to `tracing` when the `tracing` feature is enabled and fall back to the
+//! `log` facade otherwise, so nothing is written to stdout or stderr unless a logger is set up.
+
+macro_rules! info {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::info!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        log::info!($($arg)*);
+    }};
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::error!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        log::error!($($arg)*);
+    }};
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+        #[cfg(not(feature = "tracing"))]
+        log::debug!($($arg)*);
+    }};
+}
+
+/// Creates an info-level span, or a no-op stand-in without the feature.
+#[cfg(feature = "tracing")]
+macro_rules! span {
+    ($($arg:tt)*) => {
+        tracing::info_span!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! span {
+    ($($arg:tt)*) => {
+        $crate::trace::Span
+    };
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::Span;
+
+/// Stand-in for `tracing::Span` that does nothing.
+#[cfg(not(feature = "tracing"))]
+#[derive(Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn entered(self) -> Span {
+        self
+    }
+
+    pub(crate) fn in_scope<R>(&self, f: impl FnOnce() -> R) -> R {
+        f()
+    }
+
+    pub(crate) fn record<V>(&self, field: &str, value: V) -> &Self {
+        self
+    }
+}
+
+/// Attaches a span to a future that is about to be spawned.
+pub(crate) fn instrument<F: std::future::Future>(future: F, span: Span) -> impl std::future::Future<Output = F::Output> {
+    #[cfg(feature = "tracing")]
+    return tracing::Instrument::instrument(future, span);
+    #[cfg(not(feature = "tracing"))]
+    return future;
+}
+
+/// The span of the request being processed, so route macros can record their pattern on it.
+struct RequestSpan(Span);
+
+pub(crate) fn set_request_span(span: &Span) {
+    crate::context::insert(RequestSpan(span.clone()));
+}
+
+/// Records the matched route pattern on the current request span.
+pub(crate) fn record_route(pattern: &str) {
+    crate::context::with(|span: &mut RequestSpan| {
+        span.0.record("route", pattern);
+    });
+}
+
+/// The numeric status of a raw HTTP response, or 0 if it has none.
+pub(crate) fn status_of(response: &str) -> u16 {
+    response.split(' ').nth(1).and_then(|status| status.parse().ok()).unwrap_or(0)
+}
@@ -0,0 +1,204 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::Value;
+
+use crate::{json_body, request_target, Middleware, Problem};
+
+/// One way a JSON document breaks its schema.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// JSON Pointer to the offending value, `""` for the document itself.
+    pub path: String,
+    pub message: String,
+}
+
+/// A compiled JSON Schema.
+///
+/// Schemas are validated as draft 2020-12 unless their `$schema` names another draft.
+pub struct JsonSchema {
+    validator: jsonschema::Validator,
+}
+
+impl JsonSchema {
+    /// Compiles a schema, returning a message if the schema itself is invalid.
+    pub fn new(schema: &Value) -> Result<JsonSchema, String> {
+        jsonschema::validator_for(schema)
+            .map(|validator| JsonSchema { validator })
+            .map_err(|e| e.to_string())
+    }
+
+    /// Loads and compiles a schema from a JSON file.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<JsonSchema> {
+        let schema: Value = serde_json::from_str(&std::fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        JsonSchema::new(&schema).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Derives the schema of a Rust type implementing `schemars::JsonSchema`.
+    #[cfg(feature = "schemars")]
+    pub fn of<T: schemars::JsonSchema>() -> JsonSchema {
+        let schema = schemars::schema_for!(T).to_value();
+        JsonSchema::new(&schema).expect("schemars generated an invalid schema")
+    }
+
+    /// Returns every violation of the schema by `document`, or `Ok` if there are none.
+    pub fn validate(&self, document: &Value) -> Result<(), Vec<Violation>> {
+        let violations: Vec<Violation> = self
+            .validator
+            .iter_errors(document)
+            .map(|e| Violation {
+                path: e.instance_path.to_string(),
+                message: e.to_string(),
+            })
+            .collect();
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    /// Parses and validates a request body, returning the document or the problem to report:
+    /// `400` for malformed JSON, `422` listing the violations otherwise.
+    pub fn check(&self, body: &str) -> Result<Value, Problem> {
+        let document = json_body(body)?;
+        match self.validate(&document) {
+            Ok(()) => Ok(document),
+            Err(violations) => Err(unprocessable(&violations)),
+        }
+    }
+}
+
+fn unprocessable(violations: &[Violation]) -> Problem {
+    let violations: Vec<Value> = violations
+        .iter()
+        .map(|v| serde_json::json!({ "path": v.path, "message": v.message }))
+        .collect();
+    Problem::new(422)
+        .detail("The request body does not match the schema.")
+        .extension("violations", violations)
+}
+
+struct Route {
+    method: String,
+    path: String,
+    schema: Arc<JsonSchema>,
+}
+
+/// Validates JSON request bodies against per-route schemas before handlers run.
+///
+/// Routes are declared with a method and the same path prefixes used by the route macros:
+///
+/// ```text
+/// SchemaValidationMiddleware::new()
+///     .route("POST", "/items", JsonSchema::from_file("schemas/item.json")?)
+///     .route("PUT", "/items/", JsonSchema::of::<Item>())
+/// ```
+///
+/// A body that is not JSON gets a `400` problem, and one that breaks the schema gets a `422`
+/// problem whose `violations` member lists each offending value's JSON Pointer and a message.
+/// The first matching route applies; other requests pass through untouched.
+#[derive(Default)]
+pub struct SchemaValidationMiddleware {
+    routes: Vec<Route>,
+}
+
+impl SchemaValidationMiddleware {
+    pub fn new() -> SchemaValidationMiddleware {
+        SchemaValidationMiddleware::default()
+    }
+
+    /// Validates bodies of `method` requests to paths starting with `path`. `method` may be
+    /// `"*"` to match any method.
+    pub fn route(mut self, method: &str, path: &str, schema: JsonSchema) -> SchemaValidationMiddleware {
+        self.routes.push(Route {
+            method: method.to_owned(),
+            path: path.to_owned(),
+            schema: Arc::new(schema),
+        });
+        self
+    }
+}
+
+impl Middleware for SchemaValidationMiddleware {
+    fn before(&self, request: &str) -> Option<String> {
+        let (method, path, _) = request_target(request)?;
+        let route = self
+            .routes
+            .iter()
+            .find(|route| (route.method == "*" || route.method == method) && path.starts_with(&route.path))?;
+        let body = request.split_once("\r\n\r\n").map_or("", |(_, body)| body);
+        route.schema.check(body).err().map(|problem| problem.response())
+    }
+
+    fn after(&self, response: &str) -> Option<String> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn item_schema() -> JsonSchema {
+        JsonSchema::new(&json!({
+            "type": "object",
+            "required": ["name", "price"],
+            "properties": {
+                "name": { "type": "string" },
+                "price": { "type": "number", "minimum": 0 },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn middleware() -> SchemaValidationMiddleware {
+        SchemaValidationMiddleware::new().route("POST", "/items", item_schema())
+    }
+
+    fn body_of(response: &str) -> Value {
+        serde_json::from_str(response.split_once("\r\n\r\n").unwrap().1).unwrap()
+    }
+
+    #[test]
+    fn valid_bodies_pass() {
+        let request = "POST /items HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"name\": \"pen\", \"price\": 2}";
+        assert_eq!(middleware().before(request), None);
+        assert_eq!(item_schema().check("{\"name\": \"pen\", \"price\": 2}").unwrap()["name"], "pen");
+    }
+
+    #[test]
+    fn invalid_bodies_list_every_violation() {
+        let request = "POST /items HTTP/1.1\r\n\r\n{\"name\": 7, \"price\": -1}";
+        let response = middleware().before(request).unwrap();
+        assert!(response.starts_with("HTTP/1.1 422 UNPROCESSABLE ENTITY\r\n"));
+        let mut paths: Vec<String> = body_of(&response)["violations"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|violation| violation["path"].as_str().unwrap().to_owned())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["/name", "/price"]);
+
+        let violations = item_schema().validate(&json!({})).unwrap_err();
+        assert!(violations.iter().all(|violation| violation.path.is_empty()));
+        assert!(violations.iter().any(|violation| violation.message.contains("\"price\"")));
+    }
+
+    #[test]
+    fn non_json_bodies_are_bad_requests() {
+        let response = middleware().before("POST /items HTTP/1.1\r\n\r\nname=pen").unwrap();
+        assert!(response.starts_with("HTTP/1.1 400 BAD REQUEST\r\n"));
+        assert!(body_of(&response)["detail"].as_str().unwrap().starts_with("invalid JSON"));
+    }
+
+    #[test]
+    fn other_routes_pass_through() {
+        assert_eq!(middleware().before("GET /items HTTP/1.1\r\n\r\nnot json"), None);
+        assert_eq!(middleware().before("POST /users HTTP/1.1\r\n\r\nnot json"), None);
+    }
+}